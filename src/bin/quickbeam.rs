@@ -0,0 +1,84 @@
+use std::process::ExitCode;
+
+const USAGE: &str = "usage: quickbeam inspect <file> [page]
+       quickbeam bench [rows] [operations]";
+
+// Table size and steps per workload when `bench` is run without arguments
+const BENCH_ROWS: usize = 100_000;
+const BENCH_OPERATIONS: u64 = 10_000;
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match args.split_first() {
+        Some((command, args)) if command == "inspect" => inspect(args),
+        Some((command, args)) if command == "bench" => bench(args),
+        _ => {
+            eprintln!("{USAGE}");
+            ExitCode::from(2)
+        }
+    }
+}
+
+fn inspect(args: &[String]) -> ExitCode {
+    let (path, page) = match args {
+        [path] => (path, None),
+        [path, page] => match page.parse::<usize>() {
+            Ok(page) => (path, Some(page)),
+            Err(_) => {
+                eprintln!("invalid page number: {page}");
+                return ExitCode::from(2);
+            }
+        },
+        _ => {
+            eprintln!("{USAGE}");
+            return ExitCode::from(2);
+        }
+    };
+
+    // Without a page number, print every page until the end of the file
+    let pages = match page {
+        Some(page) => page..page + 1,
+        None => 0..usize::MAX,
+    };
+    for page_num in pages {
+        match quickbeam::inspect_page(path, page_num) {
+            Ok(info) => print!("{info}"),
+            Err(e) if page.is_none() && e.kind() == std::io::ErrorKind::UnexpectedEof => break,
+            Err(e) => {
+                eprintln!("{path}: {e}");
+                return ExitCode::FAILURE;
+            }
+        }
+    }
+    ExitCode::SUCCESS
+}
+
+fn bench(args: &[String]) -> ExitCode {
+    let (rows, operations) = match args {
+        [] => (Ok(BENCH_ROWS), Ok(BENCH_OPERATIONS)),
+        [rows] => (rows.parse(), Ok(BENCH_OPERATIONS)),
+        [rows, operations] => (rows.parse(), operations.parse()),
+        _ => {
+            eprintln!("{USAGE}");
+            return ExitCode::from(2);
+        }
+    };
+    let (Ok(rows), Ok(operations)) = (rows, operations) else {
+        eprintln!("invalid row or operation count: {}", args.join(" "));
+        return ExitCode::from(2);
+    };
+
+    println!("{rows} rows, {operations} operations per workload");
+    match quickbeam::bench(rows, operations) {
+        Ok(reports) => {
+            for report in reports {
+                print!("{report}");
+            }
+            ExitCode::SUCCESS
+        }
+        Err(e) => {
+            eprintln!("bench: {e}");
+            ExitCode::FAILURE
+        }
+    }
+}
@@ -1,13 +1,36 @@
-#![feature(test)]
+#![cfg_attr(test, feature(test))]
 #![allow(dead_code)]
 
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::cell::{Ref, RefCell, RefMut};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::fmt;
 use std::fs::OpenOptions;
 use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::ops::{Bound, RangeBounds, RangeInclusive};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
 
 const PAGE_SIZE: usize = 4096;
-const ROWS_PER_PAGE: usize = PAGE_SIZE / std::mem::size_of::<Row>();
+// Filtered pages are stored behind their length, see Pager::filter_page
+const FILTERED_LENGTH_SIZE: usize = 4;
+// Largest encoded leaf header: enum tag, parent, size, row count and next leaf
+const LEAF_HEADER_SIZE: usize = 4 + 9 + 8 + 8 + 9;
+// Leaves hold as many rows as fit in this many encoded bytes, so the fanout
+// follows the actual row sizes. A full leaf still fits behind the length of
+// a filtered page
+const LEAF_PAYLOAD: usize = PAGE_SIZE - FILTERED_LENGTH_SIZE - LEAF_HEADER_SIZE;
+// There are no overflow pages, so rows are capped to keep both halves of a
+// split within a page
+const MAX_ROW_SIZE: usize = LEAF_PAYLOAD / 4;
+const CHILDREN_PER_PAGE: usize = PAGE_SIZE / std::mem::size_of::<(NodeId, i32)>();
+const DEFAULT_CACHE_SIZE: usize = 1024;
+// Upper bound on pages evicted per page load, so shrinking the cache is gradual
+const EVICTION_BATCH: usize = 8;
+const DEFAULT_BUFFER_POOL_LIMIT: usize = 16 * PAGE_SIZE;
 
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
 struct Row {
@@ -43,7 +66,9 @@ struct Leaf {
 impl Leaf {
     fn get_row(&self, key: i32) -> Option<&Row> {
         // Uses binary search to find partition point
-        self.values.get(self.values.partition_point(|v| v.id < key))
+        self.values
+            .get(self.values.partition_point(|v| v.id < key))
+            .filter(|row| row.id == key)
     }
 
     fn insert_row(&mut self, key: i32, row: Row) {
@@ -51,6 +76,11 @@ impl Leaf {
         self.values.insert(idx, row);
     }
 
+    // Encoded size of the rows, the leaf splits once this passes LEAF_PAYLOAD
+    fn row_bytes(&self) -> usize {
+        self.values.iter().map(row_size).sum()
+    }
+
     fn remove_row(&mut self, _key: i32) {
         todo!("Implement remove_row")
     }
@@ -66,7 +96,10 @@ struct Internal {
 
 impl Internal {
     fn get_child_num(&self, key: i32) -> usize {
-        self.children.partition_point(|v| v.1 < key)
+        // Keys above the last max key belong to the last child
+        self.children
+            .partition_point(|v| v.1 < key)
+            .min(self.children.len().saturating_sub(1))
     }
 }
 
@@ -97,9 +130,12 @@ impl Page {
         }
     }
 
-    fn from_bytes(bytes: &[u8]) -> Self {
-        let node: Node = bincode::deserialize(bytes).unwrap();
-        Page { node }
+    // Fails with InvalidData when the bytes aren't a page, e.g. a page filter
+    // decoded them with the wrong key
+    fn from_bytes(bytes: &[u8]) -> io::Result<Self> {
+        let node: Node = bincode::deserialize(bytes)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        Ok(Page { node })
     }
 
     fn get_row(&self, key: i32) -> Option<&Row> {
@@ -131,164 +167,4503 @@ impl Page {
     }
 }
 
-#[derive(Debug, Clone)]
-struct Pager {
-    pages: HashMap<usize, Page>,
+// Converts a key range into inclusive (start, end) bounds, widened to avoid overflow
+fn inclusive_bounds(range: &impl RangeBounds<i32>) -> (i64, i64) {
+    let start = match range.start_bound() {
+        Bound::Included(&key) => key as i64,
+        Bound::Excluded(&key) => key as i64 + 1,
+        Bound::Unbounded => i64::MIN,
+    };
+    let end = match range.end_bound() {
+        Bound::Included(&key) => key as i64,
+        Bound::Excluded(&key) => key as i64 - 1,
+        Bound::Unbounded => i64::MAX,
+    };
+    (start, end)
 }
 
-impl Pager {
-    fn new() -> io::Result<Self> {
-        Ok(Pager {
-            pages: HashMap::new(),
-        })
+fn row_size(row: &Row) -> usize {
+    bincode::serialized_size(row).unwrap() as usize
+}
+
+#[derive(Debug, Default)]
+struct RangeEstimate {
+    // Subtrees entirely inside the range, counted by their level above the leaves
+    covered: Vec<usize>,
+    rows: usize,
+    bytes: usize,
+    fanout: usize,
+    internals_sampled: usize,
+    leaf_rows: usize,
+    leaf_bytes: usize,
+    leaves_sampled: usize,
+}
+
+impl RangeEstimate {
+    fn sample_leaf(&mut self, leaf: &Leaf) {
+        self.leaves_sampled += 1;
+        self.leaf_rows += leaf.values.len();
+        self.leaf_bytes += leaf.values.iter().map(row_size).sum::<usize>();
     }
 
-    fn get_page(&mut self, page_num: usize) -> io::Result<&mut Page> {
-        if let std::collections::hash_map::Entry::Vacant(e) = self.pages.entry(page_num) {
-            let offset = (page_num * PAGE_SIZE) as u64;
-            let mut buffer = vec![0; PAGE_SIZE];
-            let mut file = OpenOptions::new()
-                .read(true)
-                .write(true)
-                .create(true)
-                .truncate(false)
-                .open("data.db")?;
-
-            if file.metadata()?.len() < (offset + PAGE_SIZE as u64) {
-                file.set_len(offset + PAGE_SIZE as u64)?;
+    // Boundary leaves are read anyway, so count their matching rows exactly
+    fn count_leaf(&mut self, leaf: &Leaf, bounds: (i64, i64)) {
+        self.sample_leaf(leaf);
+        for row in leaf.values.iter() {
+            if (bounds.0..=bounds.1).contains(&(row.id as i64)) {
+                self.rows += 1;
+                self.bytes += row_size(row);
             }
+        }
+    }
 
-            file.seek(SeekFrom::Start(offset))?;
-            file.read_exact(&mut buffer)?;
-            let page = Page::from_bytes(&buffer);
-            e.insert(page);
+    fn total(&self) -> (usize, usize) {
+        if self.leaves_sampled == 0 || self.leaf_rows == 0 {
+            return (self.rows, self.bytes);
         }
-        Ok(self.pages.get_mut(&page_num).unwrap())
+        let fanout = if self.internals_sampled == 0 {
+            1.0
+        } else {
+            self.fanout as f64 / self.internals_sampled as f64
+        };
+        let rows_per_leaf = self.leaf_rows as f64 / self.leaves_sampled as f64;
+        let bytes_per_row = self.leaf_bytes as f64 / self.leaf_rows as f64;
+        let covered_rows: f64 = self
+            .covered
+            .iter()
+            .enumerate()
+            .map(|(level, &count)| count as f64 * fanout.powi(level as i32) * rows_per_leaf)
+            .sum();
+        (
+            self.rows + covered_rows.round() as usize,
+            self.bytes + (covered_rows * bytes_per_row).round() as usize,
+        )
     }
+}
 
-    fn flush_page(&self, page_num: usize) -> io::Result<()> {
-        if let Some(page) = self.pages.get(&page_num) {
-            let offset = (page_num * PAGE_SIZE) as u64;
-            let mut file = OpenOptions::new()
-                .read(true)
-                .write(true)
-                .create(true)
-                .truncate(false)
-                .open("data.db")?;
-            file.seek(SeekFrom::Start(offset))?;
-            file.write_all(&bincode::serialize(&page.node).unwrap())?;
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+enum SyncMode {
+    // Leave durability to the OS
+    Off,
+    // fsync once after each batch of page writes
+    #[default]
+    Normal,
+    // fsync after every page write
+    Full,
+}
+
+/// Size limits checked before a write, so a constrained device gets a clear
+/// error instead of a full disk
+#[derive(Debug, Clone, Copy, Default)]
+struct Limits {
+    max_file_size: Option<u64>,
+    max_rows: Option<usize>,
+    // Serialized size of a single row
+    max_row_size: Option<usize>,
+}
+
+fn read_only() -> io::Error {
+    io::Error::new(io::ErrorKind::PermissionDenied, "table is open read-only")
+}
+
+fn quota_exceeded(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::QuotaExceeded, msg)
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+struct PagerStats {
+    // Pages read from the file on a cache miss
+    page_reads: u64,
+    cache_hits: u64,
+    page_writes: u64,
+    fsyncs: u64,
+    // Page buffers allocated because the pool had none to reuse
+    buffer_allocations: u64,
+}
+
+impl PagerStats {
+    fn since(&self, earlier: &PagerStats) -> PagerStats {
+        PagerStats {
+            page_reads: self.page_reads - earlier.page_reads,
+            cache_hits: self.cache_hits - earlier.cache_hits,
+            page_writes: self.page_writes - earlier.page_writes,
+            fsyncs: self.fsyncs - earlier.fsyncs,
+            buffer_allocations: self.buffer_allocations - earlier.buffer_allocations,
         }
-        Ok(())
     }
+}
 
-    fn find_page_by_key(&mut self, key: i32) -> Option<Page> {
-        let mut page_num = 0;
-        let mut page = self.get_page(page_num).unwrap();
-        let mut node = match &page.node {
-            Node::Leaf(_) => panic!("Root should be an internal node"),
-            Node::Internal(internal) => internal,
+// Scratch buffers for page reads and writes, reused instead of allocating a
+// fresh Vec for every page. Returned buffers are freed once `limit` bytes are
+// already held
+#[derive(Debug)]
+struct BufferPool {
+    free: Vec<Vec<u8>>,
+    limit: usize,
+    // Capacity of the buffers in `free`
+    retained: usize,
+}
+
+impl BufferPool {
+    fn new(limit: usize) -> Self {
+        BufferPool {
+            free: Vec::new(),
+            limit,
+            retained: 0,
+        }
+    }
+
+    fn take(&mut self, stats: &mut PagerStats) -> Vec<u8> {
+        match self.free.pop() {
+            Some(buffer) => {
+                self.retained -= buffer.capacity();
+                buffer
+            }
+            None => {
+                stats.buffer_allocations += 1;
+                Vec::with_capacity(PAGE_SIZE)
+            }
+        }
+    }
+
+    fn give_back(&mut self, mut buffer: Vec<u8>) {
+        if self.retained + buffer.capacity() <= self.limit {
+            buffer.clear();
+            self.retained += buffer.capacity();
+            self.free.push(buffer);
+        }
+    }
+
+    fn set_limit(&mut self, limit: usize) {
+        self.limit = limit;
+        while self.retained > self.limit {
+            let buffer = self.free.pop().unwrap();
+            self.retained -= buffer.capacity();
+        }
+    }
+}
+
+// Lays `rows` out as a fresh tree with the root on page 0 and the leaves in key
+// order from page 1, returning the pages by page number and the leaf count.
+// Each leaf takes rows until the next would push it past `leaf_budget` bytes
+fn build_tree(rows: &[Row], leaf_budget: usize, internal_capacity: usize) -> (Vec<Page>, usize) {
+    // Page 0 is reserved for the root, leaves follow in key order
+    let mut pages = HashMap::new();
+    let mut next_page_num = 1;
+    let mut level = Vec::new();
+    let mut chunks = vec![Vec::new()];
+    let mut chunk_bytes = 0;
+    for row in rows {
+        let size = row_size(row);
+        if chunk_bytes + size > leaf_budget && !chunks.last().unwrap().is_empty() {
+            chunks.push(Vec::new());
+            chunk_bytes = 0;
+        }
+        chunks.last_mut().unwrap().push(row.clone());
+        chunk_bytes += size;
+    }
+    let leaf_count = chunks.len();
+    for (i, values) in chunks.into_iter().enumerate() {
+        let max_key = values.last().map_or(i32::MAX, |row| row.id);
+        level.push((next_page_num, max_key));
+        let leaf = Leaf {
+            parent_node: None,
+            size: values.len(),
+            values,
+            next_leaf: (i + 1 < leaf_count).then_some(next_page_num + 1),
         };
+        pages.insert(
+            next_page_num,
+            Page {
+                node: Node::Leaf(leaf),
+            },
+        );
+        next_page_num += 1;
+    }
 
-        loop {
-            let child_num = node.get_child_num(key);
-            page_num = node.children[child_num].0;
-            page = self.get_page(page_num).unwrap();
-            node = match &page.node {
-                Node::Leaf(_) => return Some(page.clone()),
-                Node::Internal(internal) => internal,
+    while level.len() > CHILDREN_PER_PAGE {
+        let mut parents = Vec::new();
+        for children in level.chunks(internal_capacity) {
+            parents.push((next_page_num, children.last().unwrap().1));
+            let internal = Internal {
+                parent_node: None,
+                size: children.len(),
+                children: children.to_vec(),
             };
+            pages.insert(
+                next_page_num,
+                Page {
+                    node: Node::Internal(internal),
+                },
+            );
+            next_page_num += 1;
         }
+        level = parents;
     }
+    let root = Internal {
+        parent_node: None,
+        size: level.len(),
+        children: level,
+    };
+    pages.insert(
+        0,
+        Page {
+            node: Node::Internal(root),
+        },
+    );
 
-    fn find_row_by_key(&mut self, key: i32) -> Option<Row> {
-        let page = self.find_page_by_key(key)?;
-        page.get_row(key).cloned()
+    // Point every child back at its new parent
+    let links: Vec<(NodeId, NodeId)> = pages
+        .iter()
+        .filter_map(|(&page_num, page)| match &page.node {
+            Node::Internal(internal) => Some((page_num, internal.children.clone())),
+            Node::Leaf(_) => None,
+        })
+        .flat_map(|(parent, children)| children.into_iter().map(move |(c, _)| (c, parent)))
+        .collect();
+    for (child, parent) in links {
+        match &mut pages.get_mut(&child).unwrap().node {
+            Node::Leaf(leaf) => leaf.parent_node = Some(parent),
+            Node::Internal(internal) => internal.parent_node = Some(parent),
+        }
     }
 
-    fn insert_row(&mut self, key: i32, row: Row) {
-        let mut page = self.find_page_by_key(key).unwrap();
-        page.insert_row(key, row);
-        // TODO - Split page if necessary
+    let pages = (0..next_page_num)
+        .map(|page_num| pages.remove(&page_num).unwrap())
+        .collect();
+    (pages, leaf_count)
+}
+
+/// Where the space in and around the database file goes
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+struct DiskUsage {
+    file_bytes: u64,
+    internal_pages: usize,
+    leaf_pages: usize,
+    // Serialized rows, the rest of the leaf pages is slack
+    row_bytes: usize,
+    // Pages freed by deletes, reused by the next splits
+    free_pages: usize,
+    // Pages in the file that are neither in the tree nor on the free list
+    unused_pages: usize,
+    // The warm cache list kept next to the file
+    hot_list_bytes: u64,
+}
+
+/// What a single profiled operation cost, as reported by `Table::profile`
+#[derive(Debug, Clone, Copy)]
+struct Profile {
+    stats: PagerStats,
+    elapsed: Duration,
+}
+
+// Each power of two range of nanoseconds is split into this many buckets, so a
+// recorded latency is kept to within 1/16 of its value
+const HISTOGRAM_SUB_BUCKET_BITS: u32 = 4;
+
+/// Latency distribution in log-linear buckets, in the style of HdrHistogram.
+/// Memory grows with the largest value recorded, not the number of values
+#[derive(Debug, Clone, Default)]
+struct LatencyHistogram {
+    counts: Vec<u64>,
+    total: u64,
+    sum_nanos: u128,
+    max_nanos: u64,
+}
+
+impl LatencyHistogram {
+    fn bucket(nanos: u64) -> usize {
+        let sub_buckets = 1 << HISTOGRAM_SUB_BUCKET_BITS;
+        if nanos < sub_buckets {
+            return nanos as usize;
+        }
+        let shift = 63 - nanos.leading_zeros() - HISTOGRAM_SUB_BUCKET_BITS;
+        let sub_bucket = (nanos >> shift) - sub_buckets;
+        (((shift + 1) as u64) << HISTOGRAM_SUB_BUCKET_BITS) as usize + sub_bucket as usize
     }
 
-    fn split_leaf_node(&mut self, leaf_page_num: usize, parent_page_num: usize) {
-        let new_page_num = self.pages.len();
-        let page = self.get_page(leaf_page_num).unwrap();
-        let leaf = match &mut page.node {
-            Node::Leaf(leaf) => leaf,
-            Node::Internal(_) => panic!("Page should be a leaf node"),
-        };
+    // Largest value that falls in `bucket`
+    fn bucket_max(bucket: usize) -> u64 {
+        let sub_buckets = 1 << HISTOGRAM_SUB_BUCKET_BITS;
+        if bucket < sub_buckets {
+            return bucket as u64;
+        }
+        let shift = (bucket >> HISTOGRAM_SUB_BUCKET_BITS) - 1;
+        let sub_bucket = (bucket % sub_buckets) as u64;
+        ((sub_buckets as u64 + sub_bucket) << shift) + ((1 << shift) - 1)
+    }
 
-        let mut new_page = Page::new_leaf();
-        let new_leaf = match &mut new_page.node {
-            Node::Leaf(leaf) => leaf,
-            Node::Internal(_) => panic!("Page should be a leaf node"),
-        };
+    fn record(&mut self, elapsed: Duration) {
+        let nanos = u64::try_from(elapsed.as_nanos()).unwrap_or(u64::MAX);
+        let bucket = Self::bucket(nanos);
+        if self.counts.len() <= bucket {
+            self.counts.resize(bucket + 1, 0);
+        }
+        self.counts[bucket] += 1;
+        self.total += 1;
+        self.sum_nanos += nanos as u128;
+        self.max_nanos = self.max_nanos.max(nanos);
+    }
 
-        let split_point = leaf.size / 2;
-        new_leaf.values = leaf.values.split_off(split_point);
-        new_leaf.size = new_leaf.values.len();
-        leaf.size = leaf.values.len();
+    fn count(&self) -> u64 {
+        self.total
+    }
 
-        let parent_page = self.get_page(parent_page_num).unwrap();
-        let parent = match &mut parent_page.node {
-            Node::Leaf(_) => panic!("Page should be an internal node"),
-            Node::Internal(internal) => internal,
-        };
+    fn max(&self) -> Duration {
+        Duration::from_nanos(self.max_nanos)
+    }
+
+    fn mean(&self) -> Duration {
+        match self.total {
+            0 => Duration::ZERO,
+            total => Duration::from_nanos((self.sum_nanos / total as u128) as u64),
+        }
+    }
 
-        parent.children.push((new_page_num, new_leaf.values[0].id));
-        self.pages.insert(new_page_num, new_page);
+    /// The latency that `percentile` percent of recorded values are at or
+    /// below, e.g. 99.0 for p99
+    fn percentile(&self, percentile: f64) -> Duration {
+        let rank = ((percentile / 100.0 * self.total as f64).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (bucket, &count) in self.counts.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return Duration::from_nanos(Self::bucket_max(bucket).min(self.max_nanos));
+            }
+        }
+        self.max()
     }
 }
 
-#[derive(Debug)]
-struct Table {
-    pager: Pager,
+/// Operations with their own latency histogram in `Metrics`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Operation {
+    Get,
+    Insert,
+    Delete,
+    // Writing dirty pages back and syncing, the closest thing to a commit
+    Flush,
+    ScanNext,
+    PageRead,
+    PageWrite,
 }
 
-impl Table {
-    fn new() -> Self {
-        Table {
-            pager: Pager::new().expect("Error opening database file"),
-        }
+/// Latency histograms per operation since the table was opened, see
+/// `Table::metrics`
+#[derive(Debug, Clone, Default)]
+struct Metrics {
+    histograms: [LatencyHistogram; 7],
+}
+
+impl Metrics {
+    fn latency(&self, operation: Operation) -> &LatencyHistogram {
+        &self.histograms[operation as usize]
+    }
+
+    fn record(&mut self, operation: Operation, elapsed: Duration) {
+        self.histograms[operation as usize].record(elapsed);
     }
 }
 
-struct Cursor {
-    pager: Box<Pager>,
-    keys: Vec<i32>,
-    current_idx: usize,
+// SplitMix64, small and seedable so generated data and workloads repeat exactly
+#[derive(Debug, Clone)]
+struct SplitMix64(u64);
+
+impl SplitMix64 {
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    // Uniform in [0, 1)
+    fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    // Uniform in [0, n)
+    fn below(&mut self, n: u64) -> u64 {
+        ((self.next_u64() as u128 * n as u128) >> 64) as u64
+    }
 }
 
-impl Cursor {
-    fn new(mut pager: Box<Pager>, keys: Vec<i32>) -> Result<Self, io::Error> {
-        let current_idx = pager.get_page(0)?.node.get_row(keys[0]).is_some() as usize;
-        Ok(Cursor {
-            pager,
-            keys,
-            current_idx,
+// Zipfian ranks in [0, items) using the method from Gray et al., "Quickly
+// Generating Billion-Record Synthetic Databases". Rank 0 is the most popular
+#[derive(Debug, Clone)]
+struct Zipfian {
+    items: u64,
+    theta: f64,
+    alpha: f64,
+    zetan: f64,
+    eta: f64,
+}
+
+impl Zipfian {
+    fn new(items: u64, theta: f64) -> io::Result<Self> {
+        if items == 0 || !(theta > 0.0 && theta < 1.0) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "zipfian needs at least one item and a theta in (0, 1)",
+            ));
+        }
+        let zeta = |n: u64| (1..=n).map(|i| 1.0 / (i as f64).powf(theta)).sum::<f64>();
+        let zetan = zeta(items);
+        Ok(Zipfian {
+            items,
+            theta,
+            alpha: 1.0 / (1.0 - theta),
+            zetan,
+            eta: (1.0 - (2.0 / items as f64).powf(1.0 - theta)) / (1.0 - zeta(2) / zetan),
         })
     }
 
-    fn advance(&mut self) {
-        self.current_idx += 1;
+    fn sample(&self, rng: &mut SplitMix64) -> u64 {
+        let u = rng.next_f64();
+        let uz = u * self.zetan;
+        if uz < 1.0 {
+            0
+        } else if uz < 1.0 + 0.5f64.powf(self.theta) {
+            1.min(self.items - 1)
+        } else {
+            let rank = self.items as f64 * (self.eta * u - self.eta + 1.0).powf(self.alpha);
+            (rank as u64).min(self.items - 1)
+        }
+    }
+}
+
+/// Order in which `Table::generate` inserts its keys
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum KeyOrder {
+    Sequential,
+    Random,
+}
+
+/// How benchmark workloads pick the existing keys they read
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Access {
+    Uniform,
+    // Skewed towards the smallest keys, theta in (0, 1). 0.99 matches YCSB
+    Zipfian(f64),
+}
+
+/// Shape of the synthetic rows written by `Table::generate` and `Table::bench`.
+/// The same spec and seed always produce the same rows and operations
+#[derive(Debug, Clone)]
+struct DataSpec {
+    key_order: KeyOrder,
+    // Bytes of lowercase ASCII in each generated name
+    name_len: RangeInclusive<usize>,
+    access: Access,
+    // Rows read by each operation of the scan workload
+    scan_len: usize,
+    seed: u64,
+}
+
+impl Default for DataSpec {
+    fn default() -> Self {
+        DataSpec {
+            key_order: KeyOrder::Sequential,
+            name_len: 16..=64,
+            access: Access::Uniform,
+            scan_len: 100,
+            seed: 0,
+        }
     }
+}
 
-    fn get_row(&mut self) -> Option<Row> {
-        self.pager.find_row_by_key(self.keys[self.current_idx])
+impl DataSpec {
+    fn key_order(mut self, order: KeyOrder) -> Self {
+        self.key_order = order;
+        self
     }
 
-    fn insert(&mut self, row: Row) {
-        let mut page = self
-            .pager
-            .find_page_by_key(self.keys[self.current_idx])
-            .expect("Unable to get page");
-        page.insert_row(self.keys[self.current_idx], row);
+    fn name_len(mut self, len: RangeInclusive<usize>) -> Self {
+        self.name_len = len;
+        self
+    }
+
+    fn access(mut self, access: Access) -> Self {
+        self.access = access;
+        self
+    }
+
+    fn scan_len(mut self, rows: usize) -> Self {
+        self.scan_len = rows;
+        self
+    }
+
+    fn seed(mut self, seed: u64) -> Self {
+        self.seed = seed;
+        self
+    }
+
+    // Checked before anything is generated, name() assumes a non-empty range
+    fn validate(&self) -> io::Result<()> {
+        if self.name_len.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "name length range is empty",
+            ));
+        }
+        Ok(())
+    }
+
+    fn name(&self, rng: &mut SplitMix64) -> String {
+        let (min, max) = (*self.name_len.start(), *self.name_len.end());
+        let len = min + rng.below((max - min) as u64 + 1) as usize;
+        (0..len)
+            .map(|_| (b'a' + rng.below(26) as u8) as char)
+            .collect()
     }
 }
 
-#[cfg(test)]
-mod tests {
-    // use super::*;
-    extern crate test;
+/// Standard operation mixes for `Table::bench`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Workload {
+    // Inserts rows with new keys above the generated ones
+    Load,
+    PointRead,
+    // Reads `DataSpec::scan_len` rows from a chosen key
+    Scan,
+    // Point reads with the given chance out of 100, inserts otherwise
+    Mixed { read_percent: u8 },
+}
+
+/// Throughput and per-operation latency of one `Table::bench` run, as printed
+/// by `quickbeam bench`
+#[derive(Debug, Clone)]
+pub struct BenchReport {
+    workload: Workload,
+    operations: u64,
+    elapsed: Duration,
+    latency: LatencyHistogram,
+}
+
+impl BenchReport {
+    /// Operations per second over the whole run
+    fn throughput(&self) -> f64 {
+        self.operations as f64 / self.elapsed.as_secs_f64()
+    }
+}
+
+/// An open file as the pager uses it
+trait VfsFile: Read + Write + Seek + fmt::Debug {
+    fn len(&self) -> io::Result<u64>;
+    fn set_len(&self, len: u64) -> io::Result<()>;
+    fn sync_all(&self) -> io::Result<()>;
+    fn sync_data(&self) -> io::Result<()>;
+}
+
+impl VfsFile for std::fs::File {
+    fn len(&self) -> io::Result<u64> {
+        Ok(self.metadata()?.len())
+    }
+
+    fn set_len(&self, len: u64) -> io::Result<()> {
+        std::fs::File::set_len(self, len)
+    }
+
+    fn sync_all(&self) -> io::Result<()> {
+        std::fs::File::sync_all(self)
+    }
+
+    fn sync_data(&self) -> io::Result<()> {
+        std::fs::File::sync_data(self)
+    }
+}
+
+/// Everything the pager needs from its environment: files and the clock.
+/// Installed with TableOptions::vfs, so tests and platforms without a normal
+/// file system can supply their own without changing the pager
+trait Vfs: fmt::Debug {
+    /// Opens `path` for reading, and for writing when `writable` is set, in
+    /// which case a missing file is created
+    fn open(&self, path: &Path, writable: bool) -> io::Result<Box<dyn VfsFile>>;
+    /// Size of the file at `path`, None if there is no such file
+    fn size(&self, path: &Path) -> io::Result<Option<u64>>;
+    fn remove(&self, path: &Path) -> io::Result<()>;
+    /// Moves `from` to `to`, replacing any file already at `to`. Files opened
+    /// at `to` before keep their old contents
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()>;
+    fn now(&self) -> Instant;
+
+    /// Opens `path` for writing, creating it or emptying an existing file
+    fn create(&self, path: &Path) -> io::Result<Box<dyn VfsFile>> {
+        let file = self.open(path, true)?;
+        file.set_len(0)?;
+        Ok(file)
+    }
+
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        let mut bytes = Vec::new();
+        self.open(path, false)?.read_to_end(&mut bytes)?;
+        Ok(bytes)
+    }
+
+    fn write(&self, path: &Path, bytes: &[u8]) -> io::Result<()> {
+        self.create(path)?.write_all(bytes)
+    }
+}
+
+/// The operating system's files and clock
+#[derive(Debug, Default)]
+struct OsVfs;
+
+impl Vfs for OsVfs {
+    fn open(&self, path: &Path, writable: bool) -> io::Result<Box<dyn VfsFile>> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(writable)
+            .create(writable)
+            .truncate(false)
+            .open(path)?;
+        Ok(Box::new(file))
+    }
+
+    fn size(&self, path: &Path) -> io::Result<Option<u64>> {
+        match std::fs::metadata(path) {
+            Ok(metadata) => Ok(Some(metadata.len())),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn remove(&self, path: &Path) -> io::Result<()> {
+        std::fs::remove_file(path)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        std::fs::rename(from, to)
+    }
+
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// Decides which cached page to evict. The pager reports every page entering,
+/// being read from and leaving the cache, so implementations only need to track
+/// what they care about
+trait EvictionPolicy: fmt::Debug {
+    fn inserted(&mut self, page_num: usize);
+    fn accessed(&mut self, page_num: usize);
+    fn removed(&mut self, page_num: usize);
+    /// Picks the next page to evict among those `can_evict` allows, as pinned
+    /// pages can't be evicted
+    fn victim(&mut self, can_evict: &dyn Fn(usize) -> bool) -> Option<usize>;
+}
+
+/// Transforms page bytes on their way to and from the file, e.g. to encrypt or
+/// compress them. Filters run in the order they were added when a page is
+/// written and in reverse when it is read. The output must fit in a page,
+/// less the four byte length stored in front of it
+trait PageFilter: fmt::Debug {
+    fn encode(&self, page_num: usize, bytes: &[u8]) -> io::Result<Vec<u8>>;
+    fn decode(&self, page_num: usize, bytes: &[u8]) -> io::Result<Vec<u8>>;
+}
+
+/// Evicts the least recently used page
+#[derive(Debug, Default)]
+struct LruPolicy {
+    tick: u64,
+    last_used: HashMap<usize, u64>,
+    // Pages ordered by last use, oldest first
+    by_age: BTreeMap<u64, usize>,
+}
+
+impl LruPolicy {
+    fn touch(&mut self, page_num: usize) {
+        self.tick += 1;
+        if let Some(old) = self.last_used.insert(page_num, self.tick) {
+            self.by_age.remove(&old);
+        }
+        self.by_age.insert(self.tick, page_num);
+    }
+}
+
+impl EvictionPolicy for LruPolicy {
+    fn inserted(&mut self, page_num: usize) {
+        self.touch(page_num);
+    }
+
+    fn accessed(&mut self, page_num: usize) {
+        self.touch(page_num);
+    }
+
+    fn removed(&mut self, page_num: usize) {
+        if let Some(tick) = self.last_used.remove(&page_num) {
+            self.by_age.remove(&tick);
+        }
+    }
+
+    fn victim(&mut self, can_evict: &dyn Fn(usize) -> bool) -> Option<usize> {
+        self.by_age
+            .values()
+            .copied()
+            .find(|&page_num| can_evict(page_num))
+    }
+}
+
+/// Second chance eviction: a hand sweeps the cached pages, clearing reference
+/// bits and evicting the first page that wasn't used since the last sweep
+#[derive(Debug, Default)]
+struct ClockPolicy {
+    // (page_num, referenced), with None for slots freed by removed pages
+    slots: Vec<Option<(usize, bool)>>,
+    positions: HashMap<usize, usize>,
+    free: Vec<usize>,
+    hand: usize,
+}
+
+impl EvictionPolicy for ClockPolicy {
+    fn inserted(&mut self, page_num: usize) {
+        let slot = match self.free.pop() {
+            Some(slot) => slot,
+            None => {
+                self.slots.push(None);
+                self.slots.len() - 1
+            }
+        };
+        self.slots[slot] = Some((page_num, false));
+        self.positions.insert(page_num, slot);
+    }
+
+    fn accessed(&mut self, page_num: usize) {
+        if let Some(&slot) = self.positions.get(&page_num) {
+            self.slots[slot] = Some((page_num, true));
+        }
+    }
+
+    fn removed(&mut self, page_num: usize) {
+        if let Some(slot) = self.positions.remove(&page_num) {
+            self.slots[slot] = None;
+            self.free.push(slot);
+        }
+    }
+
+    fn victim(&mut self, can_evict: &dyn Fn(usize) -> bool) -> Option<usize> {
+        // Two full sweeps clear every reference bit, so a third finds nothing new
+        for _ in 0..self.slots.len() * 2 {
+            let slot = self.hand;
+            self.hand = (self.hand + 1) % self.slots.len();
+            match &mut self.slots[slot] {
+                Some((page_num, _)) if !can_evict(*page_num) => {}
+                Some((_, referenced)) if *referenced => *referenced = false,
+                Some((page_num, _)) => return Some(*page_num),
+                None => {}
+            }
+        }
+        None
+    }
+}
+
+/// Read access to a cached page that keeps it pinned in the cache, so it can't
+/// be evicted while the guard is alive
+#[derive(Debug)]
+struct PageGuard {
+    page_num: usize,
+    frame: Rc<RefCell<Page>>,
+}
+
+impl PageGuard {
+    fn page(&self) -> Ref<'_, Page> {
+        self.frame.borrow()
+    }
+}
+
+/// Write access to a pinned page. The page is marked dirty when the guard is
+/// taken and stays dirty until it is written back after the guard is dropped.
+/// Don't hold the `page_mut` borrow across other pager calls
+#[derive(Debug)]
+struct PageGuardMut {
+    page_num: usize,
+    frame: Rc<RefCell<Page>>,
+}
+
+impl PageGuardMut {
+    fn page(&self) -> Ref<'_, Page> {
+        self.frame.borrow()
+    }
+
+    fn page_mut(&self) -> RefMut<'_, Page> {
+        self.frame.borrow_mut()
+    }
+}
+
+#[derive(Debug)]
+struct Pager {
+    vfs: Box<dyn Vfs>,
+    path: PathBuf,
+    // A frame is pinned while a page guard holds another reference to it
+    pages: HashMap<usize, Rc<RefCell<Page>>>,
+    // Pages modified since they were last written to disk
+    dirty: HashSet<usize>,
+    policy: Box<dyn EvictionPolicy>,
+    cache_size: usize,
+    sync_mode: SyncMode,
+    // Write dirty pages back once more than this many have accumulated
+    flush_threshold: Option<usize>,
+    // Pages have been written since the file was last fsynced
+    unsynced: bool,
+    // Number of pages in the file, including pages only allocated in memory
+    page_count: usize,
+    limits: Limits,
+    // Counted on first use when a row limit is set, then kept up to date
+    row_count: Option<usize>,
+    stats: PagerStats,
+    metrics: Metrics,
+    // Record cached pages on close and preload them on the next open
+    warm_cache: bool,
+    // A write behind an insert ran out of space. Its pages stay dirty in the
+    // cache and further changes wait until a flush gets them out
+    storage_full: bool,
+    // Bumped on every change to the tree so detached cursors know their
+    // buffered rows and leaf links may be stale
+    changes: u64,
+    // Pages dropped from the tree, handed out again before the file grows.
+    // Saved in a sidecar file on close, see save_free_pages
+    free_pages: Vec<usize>,
+    buffers: BufferPool,
+    // Applied in order on write and in reverse on read. Filtered pages are
+    // stored behind a u32 length, unfiltered ones as plain bincode
+    filters: Vec<Box<dyn PageFilter>>,
+    // Never create, extend or write the file
+    read_only: bool,
+    // Read-only tables read through the file they opened, so a snapshot
+    // exported over the path later doesn't mix into pages already cached
+    reader: Option<Box<dyn VfsFile>>,
+    // Only keys above the current maximum may be inserted and nothing is
+    // deleted, so splits leave the left page full instead of half full
+    append_only: bool,
+}
+
+impl Pager {
+    fn new() -> io::Result<Self> {
+        Self::open("data.db")
+    }
+
+    fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        Self::open_in(path, Box::new(OsVfs))
+    }
+
+    fn open_in(path: impl AsRef<Path>, vfs: Box<dyn Vfs>) -> io::Result<Self> {
+        let page_count = (vfs.size(path.as_ref())?.unwrap_or(0) as usize).div_ceil(PAGE_SIZE);
+        Ok(Pager {
+            vfs,
+            path: path.as_ref().to_path_buf(),
+            pages: HashMap::new(),
+            dirty: HashSet::new(),
+            policy: Box::new(LruPolicy::default()),
+            cache_size: DEFAULT_CACHE_SIZE,
+            sync_mode: SyncMode::default(),
+            flush_threshold: None,
+            unsynced: false,
+            page_count,
+            limits: Limits::default(),
+            row_count: None,
+            stats: PagerStats::default(),
+            metrics: Metrics::default(),
+            warm_cache: false,
+            storage_full: false,
+            changes: 0,
+            free_pages: Vec::new(),
+            buffers: BufferPool::new(DEFAULT_BUFFER_POOL_LIMIT),
+            filters: Vec::new(),
+            read_only: false,
+            reader: None,
+            append_only: false,
+        })
+    }
+
+    fn set_eviction_policy(&mut self, mut policy: Box<dyn EvictionPolicy>) {
+        for &page_num in self.pages.keys() {
+            policy.inserted(page_num);
+        }
+        self.policy = policy;
+    }
+
+    // Sidecar file listing the pages that were cached at the last close
+    fn hot_pages_path(&self) -> PathBuf {
+        let mut path = self.path.as_os_str().to_owned();
+        path.push("-hot");
+        PathBuf::from(path)
+    }
+
+    fn save_hot_pages(&self) -> io::Result<()> {
+        let mut hot: Vec<usize> = self.pages.keys().copied().collect();
+        hot.sort_unstable();
+        self.vfs
+            .write(&self.hot_pages_path(), &bincode::serialize(&hot).unwrap())
+    }
+
+    // Best effort, a missing or unreadable list just means a cold start
+    fn preload_hot_pages(&mut self) -> io::Result<()> {
+        let Ok(bytes) = self.vfs.read(&self.hot_pages_path()) else {
+            return Ok(());
+        };
+        let Ok(hot) = bincode::deserialize::<Vec<usize>>(&bytes) else {
+            return Ok(());
+        };
+        let hot: Vec<usize> = hot
+            .into_iter()
+            .filter(|&page_num| page_num < self.page_count)
+            .take(self.cache_size)
+            .collect();
+        log::info!(pages = hot.len(); "preloading hot pages");
+        for page_num in hot {
+            self.load_page(page_num)?;
+        }
+        Ok(())
+    }
+
+    // Sidecar file listing the free pages at the last close
+    fn free_pages_path(&self) -> PathBuf {
+        let mut path = self.path.as_os_str().to_owned();
+        path.push("-free");
+        PathBuf::from(path)
+    }
+
+    // Only called once the tree is on disk, the list must never name a page
+    // the saved tree still uses
+    fn save_free_pages(&self) -> io::Result<()> {
+        if self.free_pages.is_empty() {
+            return Ok(());
+        }
+        self.vfs.write(
+            &self.free_pages_path(),
+            &bincode::serialize(&self.free_pages).unwrap(),
+        )
+    }
+
+    // The list is removed once read. Pages reused after this and written out
+    // before a crash would otherwise be handed out twice on the next open, so
+    // a table that isn't closed leaks its free pages until optimize instead
+    fn load_free_pages(&mut self) -> io::Result<()> {
+        let path = self.free_pages_path();
+        let Ok(bytes) = self.vfs.read(&path) else {
+            return Ok(());
+        };
+        self.vfs.remove(&path)?;
+        let Ok(free) = bincode::deserialize::<Vec<usize>>(&bytes) else {
+            return Ok(());
+        };
+        let mut seen = HashSet::new();
+        self.free_pages = free
+            .into_iter()
+            .filter(|&page_num| page_num > 0 && page_num < self.page_count && seen.insert(page_num))
+            .collect();
+        log::info!(pages = self.free_pages.len(); "loaded free page list");
+        Ok(())
+    }
+
+    fn set_cache_size(&mut self, pages: usize) {
+        log::info!(old = self.cache_size, new = pages.max(1); "resizing page cache");
+        self.cache_size = pages.max(1);
+    }
+
+    fn set_sync_mode(&mut self, mode: SyncMode) {
+        self.sync_mode = mode;
+    }
+
+    fn set_flush_threshold(&mut self, pages: Option<usize>) {
+        self.flush_threshold = pages;
+    }
+
+    fn set_limits(&mut self, limits: Limits) {
+        self.limits = limits;
+    }
+
+    fn set_buffer_pool_limit(&mut self, bytes: usize) {
+        self.buffers.set_limit(bytes);
+    }
+
+    fn row_count(&mut self) -> io::Result<usize> {
+        if let Some(count) = self.row_count {
+            return Ok(count);
+        }
+        // Follow the leaf chain from the leftmost leaf, counting rows in place
+        let mut page_num = 0;
+        while let Node::Internal(internal) = &self.get_page(page_num)?.node {
+            match internal.children.first() {
+                Some(&(child, _)) => page_num = child,
+                None => break,
+            }
+        }
+        let mut count = 0;
+        let mut next = Some(page_num);
+        while let Some(page_num) = next {
+            let Node::Leaf(leaf) = &self.get_page(page_num)?.node else {
+                break;
+            };
+            count += leaf.values.len();
+            next = leaf.next_leaf;
+        }
+        self.row_count = Some(count);
+        Ok(count)
+    }
+
+    fn get_page(&mut self, page_num: usize) -> io::Result<RefMut<'_, Page>> {
+        self.load_page(page_num)?;
+        Ok(self.pages[&page_num].borrow_mut())
+    }
+
+    fn fetch_page(&mut self, page_num: usize) -> io::Result<PageGuard> {
+        self.load_page(page_num)?;
+        Ok(PageGuard {
+            page_num,
+            frame: Rc::clone(&self.pages[&page_num]),
+        })
+    }
+
+    fn fetch_page_mut(&mut self, page_num: usize) -> io::Result<PageGuardMut> {
+        self.load_page(page_num)?;
+        self.mark_dirty(page_num);
+        self.changes += 1;
+        Ok(PageGuardMut {
+            page_num,
+            frame: Rc::clone(&self.pages[&page_num]),
+        })
+    }
+
+    fn is_pinned(&self, page_num: usize) -> bool {
+        self.pages
+            .get(&page_num)
+            .is_some_and(|frame| Rc::strong_count(frame) > 1)
+    }
+
+    fn load_page(&mut self, page_num: usize) -> io::Result<()> {
+        if self.pages.contains_key(&page_num) {
+            self.stats.cache_hits += 1;
+            self.policy.accessed(page_num);
+        } else {
+            self.stats.page_reads += 1;
+            if let Err(e) = self.evict(EVICTION_BATCH) {
+                self.write_behind_failed(e)?;
+            }
+            let offset = (page_num * PAGE_SIZE) as u64;
+            let start = self.vfs.now();
+            let mut buffer = self.buffers.take(&mut self.stats);
+            buffer.resize(PAGE_SIZE, 0);
+            let mut opened = None;
+            let file = match &mut self.reader {
+                Some(file) => file,
+                None => opened.insert(self.vfs.open(&self.path, true)?),
+            };
+            if !self.read_only && file.len()? < (offset + PAGE_SIZE as u64) {
+                file.set_len(offset + PAGE_SIZE as u64)?;
+            }
+
+            file.seek(SeekFrom::Start(offset))?;
+            file.read_exact(&mut buffer)?;
+            let page = if self.filters.is_empty() {
+                Page::from_bytes(&buffer)
+            } else {
+                self.unfilter_page(page_num, &buffer)
+                    .and_then(|bytes| Page::from_bytes(&bytes))
+            };
+            self.buffers.give_back(buffer);
+            let page = page?;
+            self.metrics
+                .record(Operation::PageRead, self.vfs.now() - start);
+            self.pages.insert(page_num, Rc::new(RefCell::new(page)));
+            self.policy.inserted(page_num);
+            self.page_count = self.page_count.max(page_num + 1);
+        }
+        Ok(())
+    }
+
+    fn filter_page(&self, page_num: usize, bytes: &[u8]) -> io::Result<Vec<u8>> {
+        let mut filtered = bytes.to_vec();
+        for filter in &self.filters {
+            filtered = filter.encode(page_num, &filtered)?;
+        }
+        if filtered.len() > PAGE_SIZE - FILTERED_LENGTH_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "page {page_num} is {} bytes after filtering",
+                    filtered.len()
+                ),
+            ));
+        }
+        let mut stored = (filtered.len() as u32).to_le_bytes().to_vec();
+        stored.extend(filtered);
+        Ok(stored)
+    }
+
+    fn unfilter_page(&self, page_num: usize, stored: &[u8]) -> io::Result<Vec<u8>> {
+        let len = u32::from_le_bytes(stored[..FILTERED_LENGTH_SIZE].try_into().unwrap()) as usize;
+        // Never written, decode the zeroed page as an empty leaf like an
+        // unfiltered file would
+        if len == 0 {
+            return Ok(vec![0; PAGE_SIZE]);
+        }
+        let Some(filtered) = stored.get(FILTERED_LENGTH_SIZE..FILTERED_LENGTH_SIZE + len) else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("page {page_num} has a filtered length of {len} bytes"),
+            ));
+        };
+        let mut bytes = filtered.to_vec();
+        for filter in self.filters.iter().rev() {
+            bytes = filter.decode(page_num, &bytes)?;
+        }
+        Ok(bytes)
+    }
+
+    fn allocate_page(&mut self) -> usize {
+        if let Some(page_num) = self.free_pages.pop() {
+            return page_num;
+        }
+        self.page_count += 1;
+        self.page_count - 1
+    }
+
+    // Drops a page from the cache without writing it and puts it on the free list
+    fn free_page(&mut self, page_num: usize) {
+        if self.pages.remove(&page_num).is_some() {
+            self.policy.removed(page_num);
+        }
+        self.dirty.remove(&page_num);
+        self.free_pages.push(page_num);
+    }
+
+    // Adds a newly built page to the cache, to be written on the next flush
+    fn insert_page(&mut self, page_num: usize, page: Page) {
+        if self
+            .pages
+            .insert(page_num, Rc::new(RefCell::new(page)))
+            .is_none()
+        {
+            self.policy.inserted(page_num);
+        }
+        self.dirty.insert(page_num);
+    }
+
+    fn mark_dirty(&mut self, page_num: usize) {
+        self.dirty.insert(page_num);
+    }
+
+    // Makes room for one more page, writing back dirty pages before dropping them
+    fn evict(&mut self, budget: usize) -> io::Result<()> {
+        let mut evicted = 0;
+        while self.pages.len() >= self.cache_size && evicted < budget {
+            let pages = &self.pages;
+            let unpinned = |page_num: usize| {
+                pages
+                    .get(&page_num)
+                    .is_some_and(|frame| Rc::strong_count(frame) == 1)
+            };
+            let Some(page_num) = self.policy.victim(&unpinned) else {
+                break;
+            };
+            if self.dirty.contains(&page_num) {
+                log::trace!(page = page_num; "writing back dirty page before eviction");
+                self.flush_page(page_num)?;
+            }
+            self.pages.remove(&page_num);
+            self.policy.removed(page_num);
+            evicted += 1;
+        }
+        Ok(())
+    }
+
+    // Evictions write dirty pages back as a side effect, possibly in the middle
+    // of an insert or split. Running out of space there must not abandon the
+    // change half done, so the pages are kept dirty in memory and the error is
+    // reported by the next insert instead
+    fn write_behind_failed(&mut self, e: io::Error) -> io::Result<()> {
+        if e.kind() != io::ErrorKind::StorageFull {
+            return Err(e);
+        }
+        log::warn!(path:? = self.path, dirty = self.dirty.len(); "out of space writing pages back, keeping them cached");
+        self.storage_full = true;
+        Ok(())
+    }
+
+    // Writes dirty pages back once more than the flush threshold are pending.
+    // Only runs between operations, a leaf that was just inserted into may be
+    // over its size until the split that follows
+    fn write_behind(&mut self) -> io::Result<()> {
+        if self
+            .flush_threshold
+            .is_some_and(|threshold| self.dirty.len() > threshold)
+        {
+            if let Err(e) = self.flush() {
+                self.write_behind_failed(e)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Runs `op` and records how long it took under `operation`
+    fn timed<T>(&mut self, operation: Operation, op: impl FnOnce(&mut Self) -> T) -> T {
+        let start = self.vfs.now();
+        let result = op(self);
+        let elapsed = self.vfs.now() - start;
+        self.metrics.record(operation, elapsed);
+        result
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.timed(Operation::Flush, Self::write_dirty_pages)
+    }
+
+    // Writes every dirty page, then syncs according to the sync mode
+    fn write_dirty_pages(&mut self) -> io::Result<()> {
+        let mut dirty: Vec<usize> = self.dirty.iter().copied().collect();
+        dirty.sort_unstable();
+        if !dirty.is_empty() {
+            log::debug!(pages = dirty.len(), sync_mode:? = self.sync_mode; "flushing dirty pages");
+        }
+        for page_num in dirty {
+            self.flush_page(page_num)?;
+        }
+        if self.sync_mode == SyncMode::Normal && self.unsynced {
+            self.sync()?;
+        }
+        self.storage_full = false;
+        Ok(())
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        self.vfs.open(&self.path, true)?.sync_all()?;
+        self.stats.fsyncs += 1;
+        self.unsynced = false;
+        Ok(())
+    }
+
+    // Flushes and fsyncs regardless of sync mode, so everything written so far is durable
+    fn close(&mut self) -> io::Result<()> {
+        self.flush()?;
+        if self.unsynced {
+            self.sync()?;
+        }
+        if !self.read_only {
+            self.save_free_pages()?;
+            if self.warm_cache {
+                self.save_hot_pages()?;
+            }
+        }
+        log::info!(path:? = self.path; "closed database file");
+        Ok(())
+    }
+
+    fn flush_page(&mut self, page_num: usize) -> io::Result<()> {
+        let Some(frame) = self.pages.get(&page_num) else {
+            self.dirty.remove(&page_num);
+            return Ok(());
+        };
+        // A page being modified through a guard is written once the guard is done
+        let Ok(page) = frame.try_borrow() else {
+            return Ok(());
+        };
+        // A pinned page may still change through its guard, so keep it dirty
+        let pinned = Rc::strong_count(frame) > 1;
+        let mut bytes = self.buffers.take(&mut self.stats);
+        bincode::serialize_into(&mut bytes, &page.node).unwrap();
+        drop(page);
+        if !self.filters.is_empty() {
+            let filtered = self.filter_page(page_num, &bytes)?;
+            self.buffers
+                .give_back(std::mem::replace(&mut bytes, filtered));
+        } else if bytes.len() > PAGE_SIZE {
+            // Writing it would overwrite the start of the next page
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("page {page_num} is {} bytes", bytes.len()),
+            ));
+        }
+
+        let start = self.vfs.now();
+        let offset = (page_num * PAGE_SIZE) as u64;
+        let mut file = self.vfs.open(&self.path, true)?;
+        file.seek(SeekFrom::Start(offset))?;
+        // Only a page that made it to the file stops being dirty, so a failed
+        // write is retried by the next flush
+        file.write_all(&bytes)?;
+        if !pinned {
+            self.dirty.remove(&page_num);
+        }
+        self.buffers.give_back(bytes);
+        self.stats.page_writes += 1;
+        self.unsynced = true;
+        if self.sync_mode == SyncMode::Full {
+            file.sync_data()?;
+            self.stats.fsyncs += 1;
+            self.unsynced = false;
+        }
+        self.metrics
+            .record(Operation::PageWrite, self.vfs.now() - start);
+        Ok(())
+    }
+
+    fn find_page_by_key(&mut self, key: i32) -> io::Result<Page> {
+        let mut page_num = 0;
+        loop {
+            let page = self.get_page(page_num)?;
+            page_num = match &page.node {
+                // Only a freshly created file has a leaf at the root
+                Node::Leaf(_) => return Ok(page.clone()),
+                Node::Internal(internal) => internal.children[internal.get_child_num(key)].0,
+            };
+        }
+    }
+
+    fn find_row_by_key(&mut self, key: i32) -> io::Result<Option<Row>> {
+        let page = self.find_page_by_key(key)?;
+        Ok(page.get_row(key).cloned())
+    }
+
+    // Number of internal levels above the leaves, following the leftmost path
+    fn tree_height(&mut self) -> io::Result<usize> {
+        let mut height = 0;
+        let mut page_num = 0;
+        while let Node::Internal(internal) = &self.get_page(page_num)?.node {
+            match internal.children.first() {
+                Some(&(child, _)) => page_num = child,
+                None => break,
+            }
+            height += 1;
+        }
+        Ok(height)
+    }
+
+    // Splits the key space at child boundaries of the first tree level with at
+    // least `n` entries, or the leaves if no level is that wide. Entries on a
+    // level hold roughly the same number of rows, and every key of an entry is
+    // above the max key of the entry before it
+    fn shard_ranges(&mut self, n: usize) -> io::Result<Vec<RangeInclusive<i32>>> {
+        if n == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "need at least one shard",
+            ));
+        }
+        let mut level = match &self.get_page(0)?.node {
+            Node::Internal(root) => root.children.clone(),
+            Node::Leaf(_) => Vec::new(),
+        };
+        while level.len() < n {
+            let mut below = Vec::new();
+            for &(page_num, _) in &level {
+                match &self.get_page(page_num)?.node {
+                    Node::Internal(internal) => below.extend_from_slice(&internal.children),
+                    Node::Leaf(_) => break,
+                }
+            }
+            if below.len() <= level.len() {
+                break;
+            }
+            level = below;
+        }
+
+        let mut ranges = Vec::with_capacity(n);
+        let mut start = i32::MIN;
+        for shard in 1..n {
+            let index = shard * level.len() / n;
+            // The last entry's max key may be below keys routed to it
+            if index == 0 || index >= level.len() {
+                continue;
+            }
+            let end = level[index - 1].1;
+            if end < start || end == i32::MAX {
+                continue;
+            }
+            ranges.push(start..=end);
+            start = end + 1;
+        }
+        ranges.push(start..=i32::MAX);
+        Ok(ranges)
+    }
+
+    fn estimate_range(
+        &mut self,
+        page_num: usize,
+        level: usize,
+        bounds: (i64, i64),
+        estimate: &mut RangeEstimate,
+    ) -> io::Result<()> {
+        let children = match &self.get_page(page_num)?.node {
+            Node::Leaf(leaf) => {
+                estimate.count_leaf(leaf, bounds);
+                return Ok(());
+            }
+            Node::Internal(internal) => internal.children.clone(),
+        };
+        estimate.fanout += children.len();
+        estimate.internals_sampled += 1;
+
+        let mut low = i64::MIN;
+        for (i, &(child, max_key)) in children.iter().enumerate() {
+            // The last child also holds any keys above its recorded max
+            let high = if i + 1 == children.len() {
+                i64::MAX
+            } else {
+                max_key as i64
+            };
+            if high >= bounds.0 && low <= bounds.1 {
+                if bounds.0 <= low && high <= bounds.1 {
+                    estimate.covered[level - 1] += 1;
+                } else {
+                    self.estimate_range(child, level - 1, bounds, estimate)?;
+                }
+            }
+            low = max_key as i64 + 1;
+        }
+        Ok(())
+    }
+
+    fn estimate_size(&mut self, range: impl RangeBounds<i32>) -> io::Result<(usize, usize)> {
+        let bounds = inclusive_bounds(&range);
+        if bounds.0 > bounds.1 {
+            return Ok((0, 0));
+        }
+
+        let height = self.tree_height()?;
+        let mut estimate = RangeEstimate {
+            covered: vec![0; height],
+            ..Default::default()
+        };
+        self.estimate_range(0, height, bounds, &mut estimate)?;
+
+        // Fully covered subtrees were never read, so make sure there is at least
+        // one leaf to base the occupancy estimate on
+        if estimate.leaves_sampled == 0 && estimate.covered.iter().any(|&n| n > 0) {
+            let mut page_num = 0;
+            loop {
+                match &self.get_page(page_num)?.node {
+                    Node::Internal(internal) => page_num = internal.children[0].0,
+                    Node::Leaf(leaf) => {
+                        estimate.sample_leaf(leaf);
+                        break;
+                    }
+                }
+            }
+        }
+        Ok(estimate.total())
+    }
+
+    // A new file reads back as an empty leaf at page 0. Move it to its own page
+    // under an internal root so the root never has to change page number
+    fn ensure_internal_root(&mut self) -> io::Result<()> {
+        if let Node::Internal(_) = self.get_page(0)?.node {
+            return Ok(());
+        }
+        let leaf_page_num = self.allocate_page();
+        log::debug!(leaf = leaf_page_num; "moving root leaf under a new internal root");
+        let mut leaf_page = std::mem::replace(&mut *self.get_page(0)?, Page::new_internal());
+        let Node::Leaf(leaf) = &mut leaf_page.node else {
+            unreachable!()
+        };
+        leaf.parent_node = Some(0);
+        let max_key = leaf.values.last().map_or(i32::MAX, |row| row.id);
+        let mut page = self.get_page(0)?;
+        let Node::Internal(root) = &mut page.node else {
+            unreachable!()
+        };
+        root.children.push((leaf_page_num, max_key));
+        root.size = root.children.len();
+        drop(page);
+        self.mark_dirty(0);
+        self.insert_page(leaf_page_num, leaf_page);
+        Ok(())
+    }
+
+    fn insert_row(&mut self, key: i32, row: Row) -> io::Result<()> {
+        if self.read_only {
+            return Err(read_only());
+        }
+        // Refuse before changing anything while earlier pages can't be written
+        if self.storage_full {
+            self.flush()?;
+        }
+        let size = row_size(&row);
+        if size > MAX_ROW_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("row of {size} bytes exceeds the maximum of {MAX_ROW_SIZE} bytes"),
+            ));
+        }
+        if let Some(max_row_size) = self.limits.max_row_size {
+            if size > max_row_size {
+                return Err(quota_exceeded(format!(
+                    "row of {size} bytes exceeds the limit of {max_row_size} bytes"
+                )));
+            }
+        }
+        if let Some(max_rows) = self.limits.max_rows {
+            if self.row_count()? >= max_rows {
+                return Err(quota_exceeded(format!(
+                    "table already holds the maximum of {max_rows} rows"
+                )));
+            }
+        }
+        self.ensure_internal_root()?;
+        let max_file_size = self.limits.max_file_size;
+        let page_count = self.page_count;
+        let append_only = self.append_only;
+
+        // Remember the (page, child index) path so splits can walk back up
+        let mut path = Vec::new();
+        let mut page_num = 0;
+        loop {
+            let (child, raised_max) = match &mut self.get_page(page_num)?.node {
+                Node::Internal(internal) => {
+                    let child_num = internal.get_child_num(key);
+                    path.push((page_num, child_num));
+                    let entry = &mut internal.children[child_num];
+                    let raised_max = key > entry.1;
+                    entry.1 = entry.1.max(key);
+                    (entry.0, raised_max)
+                }
+                Node::Leaf(leaf) => {
+                    if leaf.get_row(key).is_some() {
+                        return Err(io::Error::new(
+                            io::ErrorKind::AlreadyExists,
+                            format!("key {key} already exists"),
+                        ));
+                    }
+                    // Keys above every max on the path land in the last leaf, so
+                    // nothing on the way down was changed for a rejected key
+                    if append_only
+                        && (leaf.next_leaf.is_some()
+                            || leaf.values.last().is_some_and(|last| last.id > key))
+                    {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidInput,
+                            format!("key {key} is below the last key of an append-only table"),
+                        ));
+                    }
+                    // A split can cascade up to the root and add one page per level
+                    // plus a new root child, so refuse before changing anything
+                    if let Some(max_file_size) = max_file_size {
+                        let needed = page_count + path.len() + 2;
+                        if leaf.row_bytes() + size > LEAF_PAYLOAD
+                            && (needed * PAGE_SIZE) as u64 > max_file_size
+                        {
+                            return Err(quota_exceeded(format!(
+                                "split would grow the file past the limit of {max_file_size} bytes"
+                            )));
+                        }
+                    }
+                    leaf.insert_row(key, row);
+                    leaf.size = leaf.values.len();
+                    break;
+                }
+            };
+            if raised_max {
+                self.mark_dirty(page_num);
+            }
+            page_num = child;
+        }
+        self.mark_dirty(page_num);
+        self.changes += 1;
+        if let Some(count) = &mut self.row_count {
+            *count += 1;
+        }
+
+        let overflow = match &self.get_page(page_num)?.node {
+            Node::Leaf(leaf) => leaf.row_bytes() > LEAF_PAYLOAD,
+            Node::Internal(_) => false,
+        };
+        if overflow {
+            self.split_leaf_node(page_num, &mut path)?;
+        }
+        Ok(())
+    }
+
+    // Removes every row with a key in `range` and returns how many there were.
+    // Walks the affected leaves along their links rather than looking up each
+    // key, and frees leaves left empty along with internal nodes left without
+    // children
+    fn delete_range(&mut self, range: impl RangeBounds<i32>) -> io::Result<usize> {
+        if self.read_only {
+            return Err(read_only());
+        }
+        if self.append_only {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "table is open append-only",
+            ));
+        }
+        if self.storage_full {
+            self.flush()?;
+        }
+        let (start, end) = inclusive_bounds(&range);
+        if start > end || start > i32::MAX as i64 || end < i32::MIN as i64 {
+            return Ok(0);
+        }
+        // Freed pages may be handed out again, so no guard may point at one
+        if self.pages.keys().any(|&page_num| self.is_pinned(page_num)) {
+            return Err(io::Error::new(
+                io::ErrorKind::ResourceBusy,
+                "cannot delete while pages are pinned",
+            ));
+        }
+        self.ensure_internal_root()?;
+
+        // Remember the path to the first leaf to find the leaf before it. An
+        // unbounded start is below i32::MIN and must not wrap when narrowed
+        let first_key = start.max(i32::MIN as i64) as i32;
+        let mut path = Vec::new();
+        let mut page_num = 0;
+        while let Node::Internal(internal) = &self.get_page(page_num)?.node {
+            let child_num = internal.get_child_num(first_key);
+            path.push((page_num, child_num));
+            page_num = internal.children[child_num].0;
+        }
+
+        let mut deleted = 0;
+        let mut emptied = Vec::new();
+        // The last leaf kept so far, whose link has to skip the emptied ones
+        let mut kept_before = None;
+        let mut next = Some(page_num);
+        while let Some(leaf_page_num) = next {
+            let mut page = self.get_page(leaf_page_num)?;
+            let Node::Leaf(leaf) = &mut page.node else {
+                panic!("Page should be a leaf node");
+            };
+            if leaf.values.first().is_some_and(|row| row.id as i64 > end) {
+                break;
+            }
+            let before = leaf.values.len();
+            leaf.values
+                .retain(|row| !(start..=end).contains(&(row.id as i64)));
+            leaf.size = leaf.values.len();
+            deleted += before - leaf.size;
+            let past_end = leaf.values.last().is_some_and(|row| row.id as i64 > end);
+            let parent = leaf.parent_node.expect("Leaves always have a parent");
+            next = leaf.next_leaf;
+            let changed = leaf.size != before;
+            let empty = leaf.values.is_empty();
+            drop(page);
+
+            if empty {
+                emptied.push((leaf_page_num, parent));
+                if kept_before.is_none() {
+                    kept_before = self.leaf_before(&path)?;
+                }
+                if let Some(kept) = kept_before {
+                    if let Node::Leaf(leaf) = &mut self.get_page(kept)?.node {
+                        leaf.next_leaf = next;
+                    }
+                    self.mark_dirty(kept);
+                }
+            } else {
+                if changed {
+                    self.mark_dirty(leaf_page_num);
+                }
+                kept_before = Some(leaf_page_num);
+            }
+            if past_end {
+                break;
+            }
+        }
+
+        let freed_before = self.free_pages.len();
+        for (leaf_page_num, parent) in emptied {
+            self.remove_child(parent, leaf_page_num)?;
+        }
+        // The root always keeps a leaf, even when the table is now empty
+        let root_empty =
+            matches!(&self.get_page(0)?.node, Node::Internal(root) if root.children.is_empty());
+        if root_empty {
+            let leaf_page_num = self.allocate_page();
+            let mut leaf_page = Page::new_leaf();
+            if let Node::Leaf(leaf) = &mut leaf_page.node {
+                leaf.parent_node = Some(0);
+            }
+            if let Node::Internal(root) = &mut self.get_page(0)?.node {
+                root.children.push((leaf_page_num, i32::MAX));
+                root.size = 1;
+            }
+            self.mark_dirty(0);
+            self.insert_page(leaf_page_num, leaf_page);
+        }
+        if let Some(count) = &mut self.row_count {
+            *count -= deleted;
+        }
+        self.changes += 1;
+        log::debug!(
+            start = start,
+            end = end,
+            rows = deleted,
+            freed_pages = self.free_pages.len() - freed_before;
+            "deleted key range"
+        );
+        Ok(deleted)
+    }
+
+    // The leaf just before the one `path` leads to, found through the deepest
+    // ancestor where the path doesn't take the first child
+    fn leaf_before(&mut self, path: &[(usize, usize)]) -> io::Result<Option<usize>> {
+        let Some(&(ancestor, child_num)) = path.iter().rev().find(|&&(_, child_num)| child_num > 0)
+        else {
+            return Ok(None);
+        };
+        let mut page_num = match &self.get_page(ancestor)?.node {
+            Node::Internal(internal) => internal.children[child_num - 1].0,
+            Node::Leaf(_) => panic!("Page should be an internal node"),
+        };
+        // Then keep to the right down to the leaves
+        loop {
+            page_num = match &self.get_page(page_num)?.node {
+                Node::Internal(internal) => internal.children.last().unwrap().0,
+                Node::Leaf(_) => return Ok(Some(page_num)),
+            };
+        }
+    }
+
+    // Unhooks `child` from `parent` and frees it, then frees the parent the same
+    // way if that left it without children. The root is never freed
+    fn remove_child(&mut self, parent: usize, child: usize) -> io::Result<()> {
+        self.free_page(child);
+        let mut page = self.get_page(parent)?;
+        let Node::Internal(internal) = &mut page.node else {
+            panic!("Page should be an internal node");
+        };
+        internal.children.retain(|&(page_num, _)| page_num != child);
+        internal.size = internal.children.len();
+        let grandparent = internal.parent_node;
+        let orphaned = internal.children.is_empty() && parent != 0;
+        drop(page);
+        self.mark_dirty(parent);
+        if orphaned {
+            self.remove_child(grandparent.expect("Only the root has no parent"), parent)?;
+        }
+        Ok(())
+    }
+
+    fn count_pages(&mut self, page_num: usize, usage: &mut DiskUsage) -> io::Result<()> {
+        let children = match &self.get_page(page_num)?.node {
+            Node::Leaf(leaf) => {
+                usage.leaf_pages += 1;
+                usage.row_bytes += leaf.values.iter().map(row_size).sum::<usize>();
+                return Ok(());
+            }
+            Node::Internal(internal) => internal.children.clone(),
+        };
+        usage.internal_pages += 1;
+        for (child, _) in children {
+            self.count_pages(child, usage)?;
+        }
+        Ok(())
+    }
+
+    fn disk_usage(&mut self) -> io::Result<DiskUsage> {
+        let file_len = |path: &Path| Ok::<_, io::Error>(self.vfs.size(path)?.unwrap_or(0));
+        let mut usage = DiskUsage {
+            file_bytes: match &self.reader {
+                Some(file) => file.len()?,
+                None => file_len(&self.path)?,
+            },
+            hot_list_bytes: file_len(&self.hot_pages_path())?,
+            ..Default::default()
+        };
+        if self.page_count > 0 {
+            self.count_pages(0, &mut usage)?;
+        }
+        usage.free_pages = self.free_pages.len();
+        usage.unused_pages = self
+            .page_count
+            .saturating_sub(usage.internal_pages + usage.leaf_pages + usage.free_pages);
+        Ok(usage)
+    }
+
+    fn collect_rows(&mut self, page_num: usize, rows: &mut Vec<Row>) -> io::Result<()> {
+        let children = match &self.get_page(page_num)?.node {
+            Node::Leaf(leaf) => {
+                rows.extend(leaf.values.iter().cloned());
+                return Ok(());
+            }
+            Node::Internal(internal) => internal.children.clone(),
+        };
+        for (child, _) in children {
+            self.collect_rows(child, rows)?;
+        }
+        Ok(())
+    }
+
+    // Rewrites the whole tree with leaves laid out contiguously in key order, each
+    // filled to `fill_factor` of its capacity, then truncates any leftover pages
+    fn optimize(&mut self, fill_factor: f64) -> io::Result<()> {
+        if self.read_only {
+            return Err(read_only());
+        }
+        if !(fill_factor > 0.0 && fill_factor <= 1.0) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "fill factor must be in (0, 1]",
+            ));
+        }
+        // Every page moves, which would leave outstanding guards pointing at stale frames
+        if self.pages.keys().any(|&page_num| self.is_pinned(page_num)) {
+            return Err(io::Error::new(
+                io::ErrorKind::ResourceBusy,
+                "cannot optimize while pages are pinned",
+            ));
+        }
+        let leaf_budget = (LEAF_PAYLOAD as f64 * fill_factor) as usize;
+        let internal_capacity = ((CHILDREN_PER_PAGE as f64 * fill_factor) as usize).max(2);
+
+        let mut rows = Vec::new();
+        self.collect_rows(0, &mut rows)?;
+        let pages_before = self.page_count;
+
+        let (pages, leaf_count) = build_tree(&rows, leaf_budget, internal_capacity);
+        let next_page_num = pages.len();
+
+        for page_num in self.pages.drain().map(|(page_num, _)| page_num) {
+            self.policy.removed(page_num);
+        }
+        self.dirty.clear();
+        for (page_num, page) in pages.into_iter().enumerate() {
+            self.insert_page(page_num, page);
+        }
+        self.page_count = next_page_num;
+        self.row_count = Some(rows.len());
+        self.free_pages.clear();
+        self.changes += 1;
+        log::info!(
+            rows = rows.len(),
+            leaves = leaf_count,
+            pages_before = pages_before,
+            pages_after = next_page_num,
+            fill_factor = fill_factor;
+            "optimized table"
+        );
+        self.flush()?;
+        self.vfs
+            .open(&self.path, true)?
+            .set_len((next_page_num * PAGE_SIZE) as u64)
+    }
+
+    // Writes a fully packed copy of the tree to `path`, returning its page count.
+    // The copy is built under a temporary name and renamed into place, so a
+    // reader never opens half of it
+    fn export_snapshot(&mut self, path: &Path) -> io::Result<usize> {
+        let mut rows = Vec::new();
+        self.collect_rows(0, &mut rows)?;
+        let (pages, _) = build_tree(&rows, LEAF_PAYLOAD, CHILDREN_PER_PAGE);
+
+        let mut partial = path.as_os_str().to_owned();
+        partial.push("-partial");
+        let partial = PathBuf::from(partial);
+        let mut out = io::BufWriter::new(self.vfs.create(&partial)?);
+        for (page_num, page) in pages.iter().enumerate() {
+            let mut bytes = bincode::serialize(&page.node).unwrap();
+            if !self.filters.is_empty() {
+                bytes = self.filter_page(page_num, &bytes)?;
+            }
+            bytes.resize(PAGE_SIZE, 0);
+            out.write_all(&bytes)?;
+        }
+        out.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+        self.vfs.rename(&partial, path)?;
+        log::info!(path:? = path, rows = rows.len(), pages = pages.len(); "exported snapshot");
+        Ok(pages.len())
+    }
+
+    // Moves the upper half of a leaf into a new page linked after it
+    fn split_leaf_node(
+        &mut self,
+        leaf_page_num: usize,
+        path: &mut Vec<(usize, usize)>,
+    ) -> io::Result<()> {
+        let new_page_num = self.allocate_page();
+        let parent_page_num = path.last().expect("Leaves always have a parent").0;
+        let append_only = self.append_only;
+        // Pinned until the parent records the split, so loading the parents can't
+        // evict the leaf in between
+        let guard = self.fetch_page_mut(leaf_page_num)?;
+        let mut page = guard.page_mut();
+        let leaf = match &mut page.node {
+            Node::Leaf(leaf) => leaf,
+            Node::Internal(_) => panic!("Page should be a leaf node"),
+        };
+
+        let mut new_page = Page::new_leaf();
+        let new_leaf = match &mut new_page.node {
+            Node::Leaf(leaf) => leaf,
+            Node::Internal(_) => panic!("Page should be a leaf node"),
+        };
+
+        // Split by bytes rather than rows so both halves have room to grow. Appends
+        // never come back to the left half, so only the new last row moves over
+        let half = leaf.row_bytes() / 2;
+        let mut left_bytes = 0;
+        let split_point = if append_only {
+            leaf.values.len() - 1
+        } else {
+            leaf.values
+                .iter()
+                .position(|row| {
+                    left_bytes += row_size(row);
+                    left_bytes >= half
+                })
+                .map_or(1, |i| i + 1)
+                .clamp(1, leaf.values.len() - 1)
+        };
+        new_leaf.values = leaf.values.split_off(split_point);
+        new_leaf.size = new_leaf.values.len();
+        new_leaf.parent_node = Some(parent_page_num);
+        new_leaf.next_leaf = leaf.next_leaf.replace(new_page_num);
+        leaf.size = leaf.values.len();
+
+        let left_max = leaf.values.last().unwrap().id;
+        let right_max = new_leaf.values.last().unwrap().id;
+        log::debug!(page = leaf_page_num, new_page = new_page_num, split_key = left_max; "split leaf");
+        drop(page);
+        self.insert_page(new_page_num, new_page);
+        self.insert_child(path, left_max, (new_page_num, right_max))
+    }
+
+    // Records a split of the child at the end of `path`, splitting the parent in turn
+    // if it overflows
+    fn insert_child(
+        &mut self,
+        path: &mut Vec<(usize, usize)>,
+        left_max: i32,
+        new_child: (NodeId, i32),
+    ) -> io::Result<()> {
+        let (page_num, child_num) = path.pop().unwrap();
+        let mut page = self.get_page(page_num)?;
+        let internal = match &mut page.node {
+            Node::Leaf(_) => panic!("Page should be an internal node"),
+            Node::Internal(internal) => internal,
+        };
+        internal.children[child_num].1 = left_max;
+        internal.children.insert(child_num + 1, new_child);
+        internal.size = internal.children.len();
+        let overflow = internal.children.len() > CHILDREN_PER_PAGE;
+        drop(page);
+        self.mark_dirty(page_num);
+
+        if overflow {
+            self.split_internal_node(page_num, path)?;
+        }
+        Ok(())
+    }
+
+    fn split_internal_node(
+        &mut self,
+        page_num: usize,
+        path: &mut Vec<(usize, usize)>,
+    ) -> io::Result<()> {
+        let append_only = self.append_only;
+        // Pinned while the new pages and the parent are updated
+        let guard = self.fetch_page_mut(page_num)?;
+        let mut page = guard.page_mut();
+        let internal = match &mut page.node {
+            Node::Leaf(_) => panic!("Page should be an internal node"),
+            Node::Internal(internal) => internal,
+        };
+        let split_point = if append_only {
+            internal.children.len() - 1
+        } else {
+            internal.children.len() / 2
+        };
+        let right = internal.children.split_off(split_point);
+        internal.size = internal.children.len();
+        let left_max = internal.children.last().unwrap().1;
+        let right_max = right.last().unwrap().1;
+        // The root's children all move down, so take them before releasing the page
+        let left = if page_num == 0 {
+            std::mem::take(&mut internal.children)
+        } else {
+            Vec::new()
+        };
+        drop(page);
+
+        if page_num == 0 {
+            // The root stays on page 0, so both halves move down into new pages
+            let left_page_num = self.allocate_page();
+            let right_page_num = self.allocate_page();
+            log::info!(left = left_page_num, right = right_page_num; "split root, tree height grew");
+            let mut page = guard.page_mut();
+            let Node::Internal(root) = &mut page.node else {
+                unreachable!()
+            };
+            root.children = vec![(left_page_num, left_max), (right_page_num, right_max)];
+            root.size = root.children.len();
+            drop(page);
+            self.add_internal_page(left_page_num, Some(0), left)?;
+            self.add_internal_page(right_page_num, Some(0), right)
+        } else {
+            let new_page_num = self.allocate_page();
+            let parent_page_num = path.last().map(|&(parent, _)| parent);
+            log::debug!(page = page_num, new_page = new_page_num; "split internal node");
+            self.add_internal_page(new_page_num, parent_page_num, right)?;
+            self.insert_child(path, left_max, (new_page_num, right_max))
+        }
+    }
+
+    fn add_internal_page(
+        &mut self,
+        page_num: usize,
+        parent_node: Option<NodeId>,
+        children: Vec<(NodeId, i32)>,
+    ) -> io::Result<()> {
+        for &(child, _) in &children {
+            match &mut self.get_page(child)?.node {
+                Node::Leaf(leaf) => leaf.parent_node = Some(page_num),
+                Node::Internal(internal) => internal.parent_node = Some(page_num),
+            }
+            self.mark_dirty(child);
+        }
+        let page = Page {
+            node: Node::Internal(Internal {
+                parent_node,
+                size: children.len(),
+                children,
+            }),
+        };
+        self.insert_page(page_num, page);
+        Ok(())
+    }
+}
+
+/// Error returned by every operation on a table after an earlier operation
+/// panicked part way through. Nothing more is written, but pages evicted or
+/// flushed during that operation are already on disk and changes that were
+/// only cached are lost, so the file may hold a partly applied operation.
+#[derive(Debug)]
+struct Poisoned;
+
+impl fmt::Display for Poisoned {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "table poisoned by a panic in an earlier operation")
+    }
+}
+
+impl std::error::Error for Poisoned {}
+
+// Runs `op` against `pager` unless `poisoned` is set, and sets it if `op`
+// panics. Table::with_pager and cursors share this
+fn catch_poison<T>(
+    pager: &mut Pager,
+    poisoned: &mut bool,
+    op: impl FnOnce(&mut Pager) -> io::Result<T>,
+) -> io::Result<T> {
+    if *poisoned {
+        return Err(io::Error::other(Poisoned));
+    }
+    match panic::catch_unwind(AssertUnwindSafe(|| op(pager))) {
+        Ok(result) => result,
+        Err(_) => {
+            log::error!(path:? = pager.path; "operation panicked, poisoning table");
+            *poisoned = true;
+            Err(io::Error::other(Poisoned))
+        }
+    }
+}
+
+fn is_poisoned(err: &io::Error) -> bool {
+    err.get_ref().is_some_and(|inner| inner.is::<Poisoned>())
+}
+
+/// Decoded view of a single page, as printed by `quickbeam inspect`
+#[derive(Debug)]
+pub struct PageInfo {
+    page_num: usize,
+    node: Node,
+    // Offset and length of each row (leaf) or child entry (internal) in the page
+    cells: Vec<(usize, usize)>,
+    used: usize,
+}
+
+/// Reads and decodes page `page_num` of the database file at `path` without
+/// loading the rest of the tree or modifying the file
+pub fn inspect_page(path: impl AsRef<Path>, page_num: usize) -> io::Result<PageInfo> {
+    let mut file = std::fs::File::open(path)?;
+    let offset = (page_num * PAGE_SIZE) as u64;
+    if file.metadata()?.len() <= offset {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            format!("page {page_num} is past the end of the file"),
+        ));
+    }
+    let mut buffer = vec![0; PAGE_SIZE];
+    file.seek(SeekFrom::Start(offset))?;
+    // The last page may not be padded out to a full page
+    let mut len = 0;
+    while len < PAGE_SIZE {
+        match file.read(&mut buffer[len..])? {
+            0 => break,
+            n => len += n,
+        }
+    }
+    let node: Node =
+        bincode::deserialize(&buffer).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+    // Mirrors bincode's layout: enum tag, then the struct fields in order
+    let tag = 4;
+    let cells = match &node {
+        Node::Leaf(leaf) => {
+            let mut offset = tag
+                + bincode::serialized_size(&leaf.parent_node).unwrap() as usize
+                + bincode::serialized_size(&leaf.size).unwrap() as usize
+                + 8;
+            leaf.values
+                .iter()
+                .map(|row| {
+                    let cell = (offset, row_size(row));
+                    offset += cell.1;
+                    cell
+                })
+                .collect()
+        }
+        Node::Internal(internal) => {
+            let start = tag
+                + bincode::serialized_size(&internal.parent_node).unwrap() as usize
+                + bincode::serialized_size(&internal.size).unwrap() as usize
+                + 8;
+            let entry = bincode::serialized_size(&(0 as NodeId, 0i32)).unwrap() as usize;
+            (0..internal.children.len())
+                .map(|i| (start + i * entry, entry))
+                .collect()
+        }
+    };
+    let used = bincode::serialized_size(&node).unwrap() as usize;
+    Ok(PageInfo {
+        page_num,
+        node,
+        cells,
+        used,
+    })
+}
+
+impl fmt::Display for PageInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (kind, parent, size) = match &self.node {
+            Node::Leaf(leaf) => ("leaf", leaf.parent_node, leaf.size),
+            Node::Internal(internal) => ("internal", internal.parent_node, internal.size),
+        };
+        writeln!(f, "page {}: {kind}", self.page_num)?;
+        match parent {
+            Some(parent) => writeln!(f, "  parent: {parent}")?,
+            None => writeln!(f, "  parent: none")?,
+        }
+        writeln!(f, "  size: {size}")?;
+        writeln!(
+            f,
+            "  used: {} bytes, free: {} bytes",
+            self.used,
+            PAGE_SIZE.saturating_sub(self.used)
+        )?;
+        match &self.node {
+            Node::Leaf(leaf) => {
+                match leaf.next_leaf {
+                    Some(next) => writeln!(f, "  next leaf: {next}")?,
+                    None => writeln!(f, "  next leaf: none")?,
+                }
+                writeln!(f, "  rows: {}", leaf.values.len())?;
+                for (row, (offset, len)) in leaf.values.iter().zip(&self.cells) {
+                    writeln!(f, "    @{offset:<5} {len:>4}B  key {}", row.id)?;
+                }
+            }
+            Node::Internal(internal) => {
+                writeln!(f, "  children: {}", internal.children.len())?;
+                for ((child, max_key), (offset, len)) in internal.children.iter().zip(&self.cells) {
+                    writeln!(
+                        f,
+                        "    @{offset:<5} {len:>4}B  page {child}, max key {max_key}"
+                    )?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Fills a temporary table with `rows` generated rows and runs `operations`
+/// steps of each standard workload against it, in the order load, point read,
+/// scan and mixed. The table is deleted afterwards
+pub fn bench(rows: usize, operations: u64) -> io::Result<Vec<BenchReport>> {
+    let spec = DataSpec::default();
+    let mut table = Table::temporary()?;
+    table.generate(rows, &spec)?;
+    [
+        Workload::Load,
+        Workload::PointRead,
+        Workload::Scan,
+        Workload::Mixed { read_percent: 50 },
+    ]
+    .into_iter()
+    .map(|workload| table.bench(workload, operations, &spec))
+    .collect()
+}
+
+impl fmt::Display for BenchReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self.workload {
+            Workload::Load => "load".to_string(),
+            Workload::PointRead => "point read".to_string(),
+            Workload::Scan => "scan".to_string(),
+            Workload::Mixed { read_percent } => format!("mixed {read_percent}% reads"),
+        };
+        writeln!(
+            f,
+            "{name:<16} {:>12.0} ops/s  p50 {:>10.1?}  p99 {:>10.1?}",
+            self.throughput(),
+            self.latency.percentile(50.0),
+            self.latency.percentile(99.0)
+        )
+    }
+}
+
+// Export archives hold rows rather than pages, so they stay readable when the
+// page format changes. Integers are little endian:
+//
+//   magic   b"QBARCHV1"
+//   frame*  kind: u8, length: u32, crc32: u32, payload: [u8; length]
+//
+// Payloads are deflate compressed and the checksum covers the decompressed
+// bytes. A schema frame comes first, then row frames each holding a bincode
+// encoded Vec<Row> in key order, then an end frame with the u64 row count
+const ARCHIVE_MAGIC: &[u8; 8] = b"QBARCHV1";
+const ARCHIVE_SCHEMA: &str = "id i32, name string";
+const ARCHIVE_BATCH: usize = 1024;
+const FRAME_SCHEMA: u8 = 1;
+const FRAME_ROWS: u8 = 2;
+const FRAME_END: u8 = 3;
+
+fn invalid_archive(msg: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.into())
+}
+
+fn write_frame(out: &mut impl Write, kind: u8, payload: &[u8]) -> io::Result<()> {
+    let compressed = miniz_oxide::deflate::compress_to_vec(payload, 6);
+    let len = u32::try_from(compressed.len())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "archive frame too large"))?;
+    out.write_all(&[kind])?;
+    out.write_all(&len.to_le_bytes())?;
+    out.write_all(&crc32fast::hash(payload).to_le_bytes())?;
+    out.write_all(&compressed)
+}
+
+fn read_archive_bytes(input: &mut impl Read, buf: &mut [u8]) -> io::Result<()> {
+    input.read_exact(buf).map_err(|e| match e.kind() {
+        io::ErrorKind::UnexpectedEof => invalid_archive("archive is truncated"),
+        _ => e,
+    })
+}
+
+fn read_frame(input: &mut impl Read) -> io::Result<(u8, Vec<u8>)> {
+    let mut header = [0; 9];
+    read_archive_bytes(input, &mut header)?;
+    let len = u32::from_le_bytes(header[1..5].try_into().unwrap()) as usize;
+    let checksum = u32::from_le_bytes(header[5..9].try_into().unwrap());
+    let mut compressed = vec![0; len];
+    read_archive_bytes(input, &mut compressed)?;
+    let payload = miniz_oxide::inflate::decompress_to_vec(&compressed)
+        .map_err(|e| invalid_archive(format!("corrupt archive frame: {e}")))?;
+    if crc32fast::hash(&payload) != checksum {
+        return Err(invalid_archive("archive frame checksum mismatch"));
+    }
+    Ok((header[0], payload))
+}
+
+// Reads and verifies a whole archive, passing each batch of rows to `on_rows`.
+// Returns the number of rows read
+fn read_archive(
+    input: impl Read,
+    mut on_rows: impl FnMut(Vec<Row>) -> io::Result<()>,
+) -> io::Result<u64> {
+    let mut input = io::BufReader::new(input);
+    let mut magic = [0; 8];
+    read_archive_bytes(&mut input, &mut magic)?;
+    if &magic != ARCHIVE_MAGIC {
+        return Err(invalid_archive("not a quickbeam archive"));
+    }
+    let (kind, schema) = read_frame(&mut input)?;
+    if kind != FRAME_SCHEMA || schema != ARCHIVE_SCHEMA.as_bytes() {
+        return Err(invalid_archive("unsupported archive schema"));
+    }
+    let mut rows = 0;
+    loop {
+        match read_frame(&mut input)? {
+            (FRAME_ROWS, payload) => {
+                let batch: Vec<Row> = bincode::deserialize(&payload)
+                    .map_err(|e| invalid_archive(format!("corrupt archive rows: {e}")))?;
+                rows += batch.len() as u64;
+                on_rows(batch)?;
+            }
+            (FRAME_END, payload) => {
+                let expected = payload
+                    .try_into()
+                    .map(u64::from_le_bytes)
+                    .map_err(|_| invalid_archive("corrupt archive end frame"))?;
+                if expected != rows {
+                    return Err(invalid_archive(format!(
+                        "archive holds {rows} rows, expected {expected}"
+                    )));
+                }
+                return Ok(rows);
+            }
+            (kind, _) => {
+                return Err(invalid_archive(format!(
+                    "unknown archive frame kind {kind}"
+                )))
+            }
+        }
+    }
+}
+
+/// Settings applied when a table is opened
+#[derive(Debug)]
+struct TableOptions {
+    cache_size: usize,
+    eviction_policy: Box<dyn EvictionPolicy>,
+    warm_cache: bool,
+    filters: Vec<Box<dyn PageFilter>>,
+    read_only: bool,
+    append_only: bool,
+    vfs: Box<dyn Vfs>,
+}
+
+impl Default for TableOptions {
+    fn default() -> Self {
+        TableOptions {
+            cache_size: DEFAULT_CACHE_SIZE,
+            eviction_policy: Box::new(LruPolicy::default()),
+            warm_cache: false,
+            filters: Vec::new(),
+            read_only: false,
+            append_only: false,
+            vfs: Box::new(OsVfs),
+        }
+    }
+}
+
+impl TableOptions {
+    fn cache_size(mut self, pages: usize) -> Self {
+        self.cache_size = pages;
+        self
+    }
+
+    fn eviction_policy(mut self, policy: impl EvictionPolicy + 'static) -> Self {
+        self.eviction_policy = Box::new(policy);
+        self
+    }
+
+    /// Remember which pages were cached when the table is closed and load them
+    /// again on the next open with this option, skipping the cold cache warm-up
+    fn warm_cache(mut self, enabled: bool) -> Self {
+        self.warm_cache = enabled;
+        self
+    }
+
+    /// Adds a filter to the end of the page filter chain. A file written with
+    /// filters can only be read back with the same chain, and not by
+    /// inspect_page
+    fn page_filter(mut self, filter: impl PageFilter + 'static) -> Self {
+        self.filters.push(Box::new(filter));
+        self
+    }
+
+    /// Open without ever writing to the file, for example a snapshot shared
+    /// with other processes. Inserts and optimize fail with PermissionDenied
+    fn read_only(mut self, enabled: bool) -> Self {
+        self.read_only = enabled;
+        self
+    }
+
+    /// Only accept keys above the current maximum and refuse deletes with
+    /// PermissionDenied, for audit logs and other write-once data. Pages fill
+    /// completely before splitting. The mode isn't stored in the file, so every
+    /// writer has to open the table with it
+    fn append_only(mut self, enabled: bool) -> Self {
+        self.append_only = enabled;
+        self
+    }
+
+    /// Routes the table's file access and clock through `vfs` instead of the
+    /// operating system
+    fn vfs(mut self, vfs: impl Vfs + 'static) -> Self {
+        self.vfs = Box::new(vfs);
+        self
+    }
+
+    /// Opens an empty temporary table with these options, see
+    /// `Table::temporary`. The file is named in the system temp directory but
+    /// created through the options' VFS
+    fn temporary(self) -> io::Result<Table> {
+        static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        let path =
+            std::env::temp_dir().join(format!("quickbeam-temp-{}-{id}.db", std::process::id()));
+        // Left behind by an earlier process with the same pid
+        let _ = self.vfs.remove(&path);
+        let mut table = self.open(path)?;
+        table.temporary = true;
+        Ok(table)
+    }
+
+    fn open(self, path: impl AsRef<Path>) -> io::Result<Table> {
+        let mut pager = Pager::open_in(path, self.vfs)?;
+        pager.filters = self.filters;
+        pager.read_only = self.read_only;
+        if self.read_only {
+            pager.reader = Some(pager.vfs.open(&pager.path, false)?);
+        } else {
+            pager.load_free_pages()?;
+        }
+        pager.append_only = self.append_only;
+        pager.set_cache_size(self.cache_size);
+        pager.set_eviction_policy(self.eviction_policy);
+        pager.warm_cache = self.warm_cache;
+        if self.warm_cache {
+            pager.preload_hot_pages()?;
+        }
+        Ok(Table {
+            pager,
+            poisoned: false,
+            temporary: false,
+            closed: false,
+        })
+    }
+}
+
+#[derive(Debug)]
+struct Table {
+    pager: Pager,
+    poisoned: bool,
+    // The file is scratch space, deleted instead of written back on drop
+    temporary: bool,
+    // close() already wrote the pages back or reported why it couldn't
+    closed: bool,
+}
+
+impl Table {
+    fn new() -> Self {
+        Table::open("data.db").expect("Error opening database file")
+    }
+
+    fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        Table::open_options().open(path)
+    }
+
+    fn open_options() -> TableOptions {
+        TableOptions::default()
+    }
+
+    /// Opens an empty table for intermediate results in the system temp
+    /// directory. Pages are only written on eviction or past the flush
+    /// threshold, and the file is deleted when the table is closed or dropped
+    fn temporary() -> io::Result<Self> {
+        TableOptions::default().temporary()
+    }
+
+    // Runs `op` against the pager, poisoning the table if it panics so a half
+    // finished update is never read or written back
+    fn with_pager<T>(&mut self, op: impl FnOnce(&mut Pager) -> io::Result<T>) -> io::Result<T> {
+        catch_poison(&mut self.pager, &mut self.poisoned, |pager| {
+            op(pager).and_then(|value| pager.write_behind().map(|()| value))
+        })
+    }
+
+    /// Breaks the file down into tree pages, row data and unreferenced pages.
+    /// Reads every page of the tree
+    fn disk_usage(&mut self) -> io::Result<DiskUsage> {
+        self.with_pager(|pager| pager.disk_usage())
+    }
+
+    /// Page cache and I/O counters since the table was opened
+    fn stats(&self) -> PagerStats {
+        self.pager.stats
+    }
+
+    /// Latency histograms per operation since the table was opened
+    fn metrics(&self) -> &Metrics {
+        &self.pager.metrics
+    }
+
+    /// Runs `op` and reports the pages it read, hit in cache and wrote, along
+    /// with the wall time it took
+    fn profile<T>(&mut self, op: impl FnOnce(&mut Table) -> T) -> (T, Profile) {
+        let before = self.pager.stats;
+        let start = self.pager.vfs.now();
+        let result = op(self);
+        let profile = Profile {
+            stats: self.pager.stats.since(&before),
+            elapsed: self.pager.vfs.now() - start,
+        };
+        (result, profile)
+    }
+
+    fn get(&mut self, key: i32) -> io::Result<Option<Row>> {
+        self.with_pager(|pager| pager.timed(Operation::Get, |pager| pager.find_row_by_key(key)))
+    }
+
+    fn insert(&mut self, row: Row) -> io::Result<()> {
+        self.with_pager(|pager| {
+            pager.timed(Operation::Insert, |pager| pager.insert_row(row.id, row))
+        })
+    }
+
+    /// Opens a cursor at the first row with a key of at least `start`
+    fn cursor(&mut self, start: i32) -> io::Result<Cursor<'_>> {
+        Cursor::new(&mut self.pager, &mut self.poisoned, start)
+    }
+
+    /// Opens a cursor at the first row with a key of at least `start` that
+    /// doesn't borrow the table, so the table can change between steps
+    fn detached_cursor(&mut self, start: i32) -> io::Result<DetachedCursor> {
+        let cursor = self.cursor(start)?;
+        Ok(DetachedCursor {
+            resume_from: Some(start),
+            batch: cursor.batch,
+            next_leaf: cursor.next_leaf,
+            changes: self.pager.changes,
+        })
+    }
+
+    /// Inserts `n` synthetic rows with keys 0..n in the order and with the
+    /// name lengths given by `spec`. Fails with AlreadyExists if any of those
+    /// keys are already taken
+    fn generate(&mut self, n: usize, spec: &DataSpec) -> io::Result<()> {
+        let Ok(n) = i32::try_from(n) else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("cannot generate {n} rows with i32 keys"),
+            ));
+        };
+        spec.validate()?;
+        let mut rng = SplitMix64(spec.seed);
+        let mut keys: Vec<i32> = (0..n).collect();
+        if spec.key_order == KeyOrder::Random {
+            for i in (1..keys.len()).rev() {
+                keys.swap(i, rng.below(i as u64 + 1) as usize);
+            }
+        }
+        for id in keys {
+            let name = spec.name(&mut rng);
+            self.insert(Row { id, name })?;
+        }
+        Ok(())
+    }
+
+    /// Runs `operations` steps of `workload` against a table filled by
+    /// `generate`, so its keys are 0..row count, and reports throughput and
+    /// latency. Keys are read as `spec.access` picks them and inserts take the
+    /// next keys above the generated ones
+    fn bench(
+        &mut self,
+        workload: Workload,
+        operations: u64,
+        spec: &DataSpec,
+    ) -> io::Result<BenchReport> {
+        spec.validate()?;
+        let rows = self.with_pager(|pager| pager.row_count())? as u64;
+        let Ok(mut next_key) = i32::try_from(rows) else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "table has too many rows for generated keys",
+            ));
+        };
+        if workload != Workload::Load && rows == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "workload reads rows but the table is empty",
+            ));
+        }
+        let zipfian = match spec.access {
+            Access::Zipfian(theta) if workload != Workload::Load => {
+                Some(Zipfian::new(rows, theta)?)
+            }
+            _ => None,
+        };
+        let mut rng = SplitMix64(spec.seed);
+        let mut latency = LatencyHistogram::default();
+        let start = self.pager.vfs.now();
+        for _ in 0..operations {
+            let read = match workload {
+                Workload::Load => false,
+                Workload::PointRead | Workload::Scan => true,
+                Workload::Mixed { read_percent } => rng.below(100) < read_percent as u64,
+            };
+            if read {
+                let key = match &zipfian {
+                    Some(zipfian) => zipfian.sample(&mut rng),
+                    None => rng.below(rows),
+                } as i32;
+                let op_start = self.pager.vfs.now();
+                if workload == Workload::Scan {
+                    for row in self.scan(key..)?.take(spec.scan_len) {
+                        row?;
+                    }
+                } else {
+                    self.get(key)?;
+                }
+                latency.record(self.pager.vfs.now() - op_start);
+            } else {
+                let row = Row {
+                    id: next_key,
+                    name: spec.name(&mut rng),
+                };
+                next_key = next_key.checked_add(1).ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::InvalidInput, "ran out of keys to insert")
+                })?;
+                let op_start = self.pager.vfs.now();
+                self.insert(row)?;
+                latency.record(self.pager.vfs.now() - op_start);
+            }
+        }
+        Ok(BenchReport {
+            workload,
+            operations,
+            elapsed: self.pager.vfs.now() - start,
+            latency,
+        })
+    }
+
+    /// Removes every row with a key in `range` and returns how many there were.
+    /// Leaves left empty go to a free list that later splits draw from. The
+    /// list is saved by close, a table that is dropped without closing cleanly
+    /// keeps those pages unused until optimize
+    fn delete_range(&mut self, range: impl RangeBounds<i32>) -> io::Result<usize> {
+        self.with_pager(|pager| pager.timed(Operation::Delete, |pager| pager.delete_range(range)))
+    }
+
+    /// Rows with keys in `range`, in ascending key order
+    fn scan(
+        &mut self,
+        range: impl RangeBounds<i32>,
+    ) -> io::Result<KeyOrdered<impl Iterator<Item = io::Result<Row>> + '_>> {
+        let (start, end) = inclusive_bounds(&range);
+        let cursor = self.cursor(start.clamp(i32::MIN as i64, i32::MAX as i64) as i32)?;
+        // Keys only grow, so the first row outside the range ends the scan
+        Ok(KeyOrdered(cursor.take_while(move |row| match row {
+            Ok(row) => (start..=end).contains(&(row.id as i64)),
+            Err(_) => true,
+        })))
+    }
+
+    /// Approximate (rows, bytes) stored under `range`, based on internal node
+    /// fanout and leaf occupancy rather than a full scan
+    fn estimate_size(&mut self, range: impl RangeBounds<i32>) -> io::Result<(usize, usize)> {
+        self.with_pager(|pager| pager.estimate_size(range))
+    }
+
+    /// Splits the whole key space into at most `n` disjoint ranges holding
+    /// roughly the same number of rows, in key order, for workers that each
+    /// scan one. Small tables may get fewer ranges. Only internal pages and a
+    /// single leaf are read
+    fn shard_ranges(&mut self, n: usize) -> io::Result<Vec<RangeInclusive<i32>>> {
+        self.with_pager(|pager| pager.shard_ranges(n))
+    }
+
+    /// Rewrites this table's pages in key order at `fill_factor`, restoring scan
+    /// locality after heavy random inserts and deletes
+    fn optimize(&mut self, fill_factor: f64) -> io::Result<()> {
+        self.with_pager(|pager| pager.optimize(fill_factor))
+    }
+
+    /// Writes a compacted copy of the table to `path` for readers in other
+    /// processes, which open it with TableOptions::read_only. The copy never
+    /// changes, so any number of readers can share it without coordination.
+    /// Exporting to the same path again replaces the file, readers that are
+    /// already open keep reading the copy they opened
+    fn export_snapshot(&mut self, path: impl AsRef<Path>) -> io::Result<usize> {
+        self.with_pager(|pager| pager.export_snapshot(path.as_ref()))
+    }
+
+    /// Writes every row to a compressed, checksummed archive at `path` and
+    /// returns the number of rows written. See ARCHIVE_MAGIC for the layout
+    fn export_archive(&mut self, path: impl AsRef<Path>) -> io::Result<u64> {
+        let mut out = io::BufWriter::new(self.pager.vfs.create(path.as_ref())?);
+        out.write_all(ARCHIVE_MAGIC)?;
+        write_frame(&mut out, FRAME_SCHEMA, ARCHIVE_SCHEMA.as_bytes())?;
+        let mut cursor = self.cursor(i32::MIN)?;
+        let mut rows = 0;
+        loop {
+            let batch = cursor.next_batch(ARCHIVE_BATCH)?;
+            if batch.is_empty() {
+                break;
+            }
+            rows += batch.len() as u64;
+            write_frame(&mut out, FRAME_ROWS, &bincode::serialize(&batch).unwrap())?;
+        }
+        write_frame(&mut out, FRAME_END, &rows.to_le_bytes())?;
+        out.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+        Ok(rows)
+    }
+
+    /// Inserts the rows of the archive at `path` into this table. The whole
+    /// archive is verified first, so a corrupt one inserts nothing, but a key
+    /// that is already present stops the import part way with AlreadyExists
+    fn import_archive(&mut self, path: impl AsRef<Path>) -> io::Result<u64> {
+        let path = path.as_ref();
+        read_archive(self.pager.vfs.open(path, false)?, |_| Ok(()))?;
+        read_archive(self.pager.vfs.open(path, false)?, |rows| {
+            rows.into_iter().try_for_each(|row| self.insert(row))
+        })
+    }
+
+    /// Resizes the page cache while the table is open. Pages above the new size
+    /// are evicted a few at a time on subsequent page loads
+    fn set_cache_size(&mut self, pages: usize) {
+        self.pager.set_cache_size(pages)
+    }
+
+    fn set_sync_mode(&mut self, mode: SyncMode) {
+        self.pager.set_sync_mode(mode)
+    }
+
+    /// Writes dirty pages back eagerly once more than `pages` are pending after
+    /// an operation, instead of waiting for eviction or an explicit flush
+    fn set_flush_threshold(&mut self, pages: Option<usize>) {
+        self.pager.set_flush_threshold(pages)
+    }
+
+    /// Limits are enforced on every following insert. Rows already stored
+    /// beyond a lowered limit are kept
+    fn set_limits(&mut self, limits: Limits) {
+        self.pager.set_limits(limits)
+    }
+
+    /// Caps the memory kept in reusable page buffers between reads and writes.
+    /// Zero allocates a fresh buffer for every page
+    fn set_buffer_pool_limit(&mut self, bytes: usize) {
+        self.pager.set_buffer_pool_limit(bytes)
+    }
+
+    /// Writes every dirty page and fsyncs the file. Dropping the table does the
+    /// same on a best-effort basis, call this to find out whether it succeeded
+    fn close(mut self) -> io::Result<()> {
+        if self.temporary {
+            return Ok(());
+        }
+        self.closed = true;
+        self.with_pager(|pager| pager.close())
+    }
+}
+
+impl Drop for Table {
+    fn drop(&mut self) {
+        // Nothing is left to do if close() already ran, and a poisoned table must
+        // not write its half finished pages back
+        if self.temporary {
+            let _ = self.pager.vfs.remove(&self.pager.path);
+        } else if !self.poisoned && !self.closed {
+            let _ = self.pager.close();
+        }
+    }
+}
+
+/// Yields items in ascending key order. Code that depends on ordering, like
+/// merge joins or range checkpoints, should ask for this type rather than any
+/// iterator
+struct KeyOrdered<I>(I);
+
+impl<I: Iterator> Iterator for KeyOrdered<I> {
+    type Item = I::Item;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.0.next()
+    }
+}
+
+/// Yields items in no particular order. Nothing returns this yet, it is the
+/// type for scans that can't promise an order, such as hash indexes
+struct Unordered<I>(I);
+
+impl<I: Iterator> Iterator for Unordered<I> {
+    type Item = I::Item;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.0.next()
+    }
+}
+
+// Dropping the guarantee is always allowed, gaining it never is
+impl<I> From<KeyOrdered<I>> for Unordered<I> {
+    fn from(ordered: KeyOrdered<I>) -> Self {
+        Unordered(ordered.0)
+    }
+}
+
+// Walks rows in key order a leaf at a time, following next_leaf links instead
+// of descending from the root for every row
+struct Cursor<'a> {
+    pager: &'a mut Pager,
+    // The table's flag, set if reading a leaf panics
+    poisoned: &'a mut bool,
+    // Rows of the current leaf at and after the cursor position
+    batch: VecDeque<Row>,
+    next_leaf: Option<NodeId>,
+}
+
+impl<'a> Cursor<'a> {
+    fn new(pager: &'a mut Pager, poisoned: &'a mut bool, start: i32) -> io::Result<Self> {
+        let page = catch_poison(pager, poisoned, |pager| pager.find_page_by_key(start))?;
+        let mut cursor = Cursor {
+            pager,
+            poisoned,
+            batch: VecDeque::new(),
+            next_leaf: None,
+        };
+        if let Node::Leaf(leaf) = page.node {
+            let skip = leaf.values.partition_point(|v| v.id < start);
+            cursor.batch = leaf.values.into_iter().skip(skip).collect();
+            cursor.next_leaf = leaf.next_leaf;
+        }
+        Ok(cursor)
+    }
+
+    // Refills the batch from the following leaves, returning false at the end
+    fn fill_batch(&mut self) -> io::Result<bool> {
+        if !self.batch.is_empty() {
+            return Ok(true);
+        }
+        let (batch, next_leaf) = (&mut self.batch, &mut self.next_leaf);
+        catch_poison(self.pager, self.poisoned, |pager| {
+            while batch.is_empty() {
+                let Some(page_num) = *next_leaf else {
+                    return Ok(false);
+                };
+                let guard = pager.fetch_page(page_num)?;
+                let page = guard.page();
+                let leaf = match &page.node {
+                    Node::Leaf(leaf) => leaf,
+                    Node::Internal(_) => panic!("Page should be a leaf node"),
+                };
+                batch.extend(leaf.values.iter().cloned());
+                *next_leaf = leaf.next_leaf;
+            }
+            Ok(true)
+        })
+    }
+
+    fn advance(&mut self) {
+        self.batch.pop_front();
+    }
+
+    fn get_row(&mut self) -> io::Result<Option<Row>> {
+        self.fill_batch()?;
+        Ok(self.batch.front().cloned())
+    }
+
+    /// Returns up to `n` rows from the cursor position, moving past them
+    fn next_batch(&mut self, n: usize) -> io::Result<Vec<Row>> {
+        let mut rows = Vec::with_capacity(n.min(self.batch.len()));
+        while rows.len() < n && self.fill_batch()? {
+            let take = (n - rows.len()).min(self.batch.len());
+            rows.extend(self.batch.drain(..take));
+        }
+        Ok(rows)
+    }
+}
+
+impl Iterator for Cursor<'_> {
+    type Item = io::Result<Row>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let start = self.pager.vfs.now();
+        let row = match self.fill_batch() {
+            Ok(true) => self.batch.pop_front().map(Ok),
+            Ok(false) => None,
+            Err(e) => Some(Err(e)),
+        };
+        let elapsed = self.pager.vfs.now() - start;
+        self.pager.metrics.record(Operation::ScanNext, elapsed);
+        row
+    }
+}
+
+/// Walks rows in key order across changes to the table, e.g. inserts or an
+/// optimize between steps. It remembers the last key it returned, and when the
+/// table has changed it seeks again from there instead of trusting buffered
+/// rows and leaf links that may have moved. Rows inserted ahead of the cursor
+/// are returned, rows inserted behind it are not
+#[derive(Debug)]
+struct DetachedCursor {
+    // The next row is the first with a key of at least this, None once the
+    // cursor has passed the end
+    resume_from: Option<i32>,
+    batch: VecDeque<Row>,
+    next_leaf: Option<NodeId>,
+    // Pager::changes when the batch was read
+    changes: u64,
+}
+
+impl DetachedCursor {
+    fn next(&mut self, table: &mut Table) -> io::Result<Option<Row>> {
+        let Some(start) = self.resume_from else {
+            return Ok(None);
+        };
+        if table.poisoned {
+            return Err(io::Error::other(Poisoned));
+        }
+        let mut cursor = if table.pager.changes == self.changes {
+            Cursor {
+                pager: &mut table.pager,
+                poisoned: &mut table.poisoned,
+                batch: std::mem::take(&mut self.batch),
+                next_leaf: self.next_leaf,
+            }
+        } else {
+            log::trace!(key = start; "table changed, re-anchoring cursor");
+            self.changes = table.pager.changes;
+            Cursor::new(&mut table.pager, &mut table.poisoned, start)?
+        };
+        let row = cursor.next().transpose()?;
+        self.batch = std::mem::take(&mut cursor.batch);
+        self.next_leaf = cursor.next_leaf;
+        self.resume_from = row.as_ref().and_then(|row| row.id.checked_add(1));
+        Ok(row)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeSet;
+    extern crate test;
+
+    // A fill factor small enough to give every row its own leaf
+    const ONE_ROW_PER_LEAF: f64 = f64::MIN_POSITIVE;
+
+    fn row(id: i32) -> Row {
+        Row {
+            id,
+            name: format!("row{id}"),
+        }
+    }
+
+    fn temp_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("quickbeam-{}-{name}.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    // Builds an in-memory two level tree with the given keys in each leaf
+    fn build_pager(leaves: &[Vec<i32>]) -> Pager {
+        let mut pager = Pager::open(temp_path("unflushed")).unwrap();
+        let mut root = Internal {
+            parent_node: None,
+            size: leaves.len(),
+            children: Vec::new(),
+        };
+        for (i, keys) in leaves.iter().enumerate() {
+            let page_num = i + 1;
+            let values: Vec<Row> = keys.iter().copied().map(row).collect();
+            root.children.push((page_num, *keys.last().unwrap()));
+            let leaf = Leaf {
+                parent_node: Some(0),
+                size: values.len(),
+                values,
+                next_leaf: (i + 1 < leaves.len()).then_some(page_num + 1),
+            };
+            let page = Page {
+                node: Node::Leaf(leaf),
+            };
+            pager.pages.insert(page_num, Rc::new(RefCell::new(page)));
+        }
+        let page = Page {
+            node: Node::Internal(root),
+        };
+        pager.pages.insert(0, Rc::new(RefCell::new(page)));
+        pager.page_count = leaves.len() + 1;
+        pager
+    }
+
+    fn root(pager: &mut Pager) -> Internal {
+        match &pager.get_page(0).unwrap().node {
+            Node::Internal(internal) => internal.clone(),
+            Node::Leaf(_) => panic!("Root should be an internal node"),
+        }
+    }
+
+    fn update_root(pager: &mut Pager, update: impl FnOnce(&mut Internal)) {
+        let guard = pager.fetch_page_mut(0).unwrap();
+        let mut page = guard.page_mut();
+        match &mut page.node {
+            Node::Internal(internal) => update(internal),
+            Node::Leaf(_) => panic!("Root should be an internal node"),
+        }
+    }
+
+    #[test]
+    fn estimate_size_counts_boundary_leaves_exactly() {
+        let mut pager = build_pager(&[(0..10).collect(), (10..20).collect(), (20..30).collect()]);
+        let (rows, bytes) = pager.estimate_size(5..15).unwrap();
+        assert_eq!(rows, 10);
+        assert_eq!(bytes, (5..15).map(|id| row_size(&row(id))).sum::<usize>());
+        assert_eq!(pager.estimate_size(40..50).unwrap(), (0, 0));
+        assert_eq!(pager.estimate_size(5..5).unwrap(), (0, 0));
+    }
+
+    #[test]
+    fn estimate_size_extrapolates_covered_leaves() {
+        let leaves: Vec<Vec<i32>> = (0..8).map(|i| (i * 10..i * 10 + 10).collect()).collect();
+        let mut pager = build_pager(&leaves);
+        // Only the two boundary leaves are read, the rest are assumed to be as full
+        let (rows, _) = pager.estimate_size(5..75).unwrap();
+        assert_eq!(rows, 70);
+        let (rows, _) = pager.estimate_size(..).unwrap();
+        assert_eq!(rows, 80);
+    }
+
+    #[test]
+    fn optimize_packs_leaves_in_key_order() {
+        // Sparse leaves stored out of key order, as left behind by random inserts
+        let mut pager = build_pager(&[vec![1, 2], vec![3], vec![4, 5, 6], vec![7]]);
+        pager.path = temp_path("optimize");
+        let first = pager.pages.remove(&1).unwrap();
+        let last = pager.pages.insert(4, first).unwrap();
+        pager.pages.insert(1, last);
+        update_root(&mut pager, |root| {
+            root.children[0].0 = 4;
+            root.children[3].0 = 1;
+        });
+        let mut table = Table {
+            pager,
+            poisoned: false,
+            temporary: false,
+            closed: false,
+        };
+        table.optimize(1.0).unwrap();
+
+        assert_eq!(root(&mut table.pager).children, vec![(1, 7)]);
+        let page = table.pager.get_page(1).unwrap();
+        let Node::Leaf(leaf) = &page.node else {
+            panic!("Page should be a leaf node")
+        };
+        assert_eq!(leaf.values, (1..=7).map(row).collect::<Vec<_>>());
+        assert_eq!(leaf.parent_node, Some(0));
+        drop(page);
+
+        // The rewritten tree is what ends up on disk
+        let len = std::fs::metadata(&table.pager.path).unwrap().len();
+        assert_eq!(len, 2 * PAGE_SIZE as u64);
+        let mut reopened = Pager::open(&table.pager.path).unwrap();
+        assert_eq!(reopened.find_row_by_key(5).unwrap(), Some(row(5)));
+        std::fs::remove_file(&table.pager.path).unwrap();
+    }
+
+    #[test]
+    fn optimize_respects_fill_factor() {
+        let leaves: Vec<Vec<i32>> = (0..4).map(|i| (i * 100..i * 100 + 100).collect()).collect();
+        let mut pager = build_pager(&leaves);
+        pager.path = temp_path("fill-factor");
+        pager.optimize(0.5).unwrap();
+
+        // Every leaf is filled up to half a page and no further
+        let budget = LEAF_PAYLOAD / 2;
+        let mut next_leaf = Some(root(&mut pager).children[0].0);
+        while let Some(page_num) = next_leaf {
+            let Node::Leaf(leaf) = pager.get_page(page_num).unwrap().node.clone() else {
+                panic!("Page should be a leaf node");
+            };
+            assert!(leaf.row_bytes() <= budget);
+            if let Some(next) = leaf.next_leaf {
+                let Node::Leaf(next) = &pager.get_page(next).unwrap().node else {
+                    panic!("Page should be a leaf node");
+                };
+                assert!(leaf.row_bytes() + row_size(&next.values[0]) > budget);
+            }
+            next_leaf = leaf.next_leaf;
+        }
+        for key in [0, 99, 250, 399] {
+            assert_eq!(pager.find_row_by_key(key).unwrap(), Some(row(key)));
+        }
+        assert!(pager.optimize(0.0).is_err());
+        std::fs::remove_file(&pager.path).unwrap();
+    }
+
+    #[test]
+    fn shrinking_cache_evicts_gradually() {
+        let leaves: Vec<Vec<i32>> = (0..40).map(|i| vec![i]).collect();
+        let mut pager = build_pager(&leaves);
+        pager.path = temp_path("cache-size");
+        pager.optimize(ONE_ROW_PER_LEAF).unwrap();
+
+        let mut table = Table::open(&pager.path).unwrap();
+        for key in 0..30 {
+            table.pager.find_row_by_key(key).unwrap().unwrap();
+        }
+        assert_eq!(table.pager.pages.len(), 31);
+
+        // Nothing is dropped until the next page load, and then only a batch
+        table.set_cache_size(4);
+        assert_eq!(table.pager.pages.len(), 31);
+        table.pager.find_row_by_key(35).unwrap().unwrap();
+        assert_eq!(table.pager.pages.len(), 31 - EVICTION_BATCH + 1);
+        for key in 0..40 {
+            table.pager.find_row_by_key(key).unwrap().unwrap();
+        }
+        assert!(table.pager.pages.len() <= 4);
+        std::fs::remove_file(&pager.path).unwrap();
+    }
+
+    #[test]
+    fn evicted_dirty_pages_are_written_back() {
+        let mut pager = build_pager(&[vec![1], vec![2]]);
+        pager.path = temp_path("write-back");
+        pager.optimize(1.0).unwrap();
+
+        let mut pager = Pager::open(&pager.path).unwrap();
+        pager.set_cache_size(1);
+        update_root(&mut pager, |root| root.size = 42);
+        pager.get_page(1).unwrap();
+        assert!(!pager.pages.contains_key(&0));
+        assert_eq!(root(&mut pager).size, 42);
+        std::fs::remove_file(&pager.path).unwrap();
+    }
+
+    #[test]
+    fn dropping_table_flushes_dirty_pages() {
+        let mut pager = build_pager(&[vec![1], vec![2]]);
+        pager.path = temp_path("drop");
+        pager.optimize(1.0).unwrap();
+        let path = pager.path.clone();
+
+        let mut table = Table::open(&path).unwrap();
+        table.pager.set_sync_mode(SyncMode::Off);
+        update_root(&mut table.pager, |root| root.size = 7);
+        drop(table);
+
+        let mut table = Table::open(&path).unwrap();
+        assert_eq!(root(&mut table.pager).size, 7);
+        update_root(&mut table.pager, |root| root.size = 9);
+        table.close().unwrap();
+
+        let mut pager = Pager::open(&path).unwrap();
+        assert_eq!(root(&mut pager).size, 9);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn inserts_split_leaves_and_internal_nodes() {
+        let path = temp_path("insert");
+        let mut table = Table::open(&path).unwrap();
+        assert_eq!(table.get(1).unwrap(), None);
+
+        // Enough rows to overflow the root's children, inserted out of order
+        let n = 80_000;
+        for i in 0..n {
+            table.insert(row((i * 7919) % n)).unwrap();
+        }
+        let err = table.insert(row(3)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+        assert_eq!(table.pager.tree_height().unwrap(), 2);
+        table.close().unwrap();
+
+        let mut table = Table::open(&path).unwrap();
+        let mut rows = Vec::new();
+        table.pager.collect_rows(0, &mut rows).unwrap();
+        assert_eq!(rows, (0..n).map(row).collect::<Vec<_>>());
+        assert_eq!(table.get(12345).unwrap(), Some(row(12345)));
+        assert_eq!(table.get(n).unwrap(), None);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn panic_mid_update_poisons_table() {
+        let path = temp_path("poisoned");
+        let mut table = Table::open(&path).unwrap();
+        table.insert(row(1)).unwrap();
+        table.close().unwrap();
+
+        // An internal node without children makes the next insert panic
+        let mut table = Table::open(&path).unwrap();
+        update_root(&mut table.pager, |root| root.children[0].0 = 2);
+        table.pager.insert_page(2, Page::new_internal());
+        let err = table.insert(row(2)).unwrap_err();
+        assert!(is_poisoned(&err));
+        assert!(is_poisoned(&table.get(1).unwrap_err()));
+        assert!(is_poisoned(&table.close().unwrap_err()));
+
+        // Nothing from the poisoned handle reached the file
+        let mut table = Table::open(&path).unwrap();
+        assert_eq!(table.get(1).unwrap(), Some(row(1)));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn splits_pin_their_pages_in_a_tiny_cache() {
+        let mut table = Table::temporary().unwrap();
+        table.set_cache_size(2);
+        for i in 0..20_000 {
+            table.insert(row((i * 7919) % 20_000)).unwrap();
+        }
+        assert!(table.stats().page_reads > 0);
+        let rows: Vec<Row> = table.scan(..).unwrap().map(Result::unwrap).collect();
+        assert_eq!(rows, (0..20_000).map(row).collect::<Vec<_>>());
+        assert!(table
+            .pager
+            .pages
+            .keys()
+            .all(|&page_num| !table.pager.is_pinned(page_num)));
+    }
+
+    #[test]
+    fn panic_in_scan_poisons_table() {
+        let mut table = Table::temporary().unwrap();
+        for i in 0..1000 {
+            table.insert(row(i)).unwrap();
+        }
+        let mut detached = table.detached_cursor(0).unwrap();
+        // A leaf linked to the root makes the cursor panic when it gets there
+        let first_leaf = root(&mut table.pager).children[0].0;
+        if let Node::Leaf(leaf) = &mut table.pager.get_page(first_leaf).unwrap().node {
+            leaf.next_leaf = Some(0);
+        }
+
+        let err = table.scan(..).unwrap().find_map(Result::err).unwrap();
+        assert!(is_poisoned(&err));
+        assert!(is_poisoned(&table.get(1).unwrap_err()));
+        assert!(is_poisoned(&table.scan(..).err().unwrap()));
+        assert!(is_poisoned(&detached.next(&mut table).unwrap_err()));
+    }
+
+    #[test]
+    fn limits_reject_inserts_before_writing() {
+        let path = temp_path("limits");
+        let mut table = Table::open(&path).unwrap();
+        for i in 0..10 {
+            table.insert(row(i)).unwrap();
+        }
+        table.close().unwrap();
+
+        // The row count is recovered from the tree after reopening
+        let mut table = Table::open(&path).unwrap();
+        table.set_limits(Limits {
+            max_rows: Some(11),
+            max_row_size: Some(32),
+            ..Default::default()
+        });
+        let long = Row {
+            id: 100,
+            name: "x".repeat(64),
+        };
+        let err = table.insert(long).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::QuotaExceeded);
+        table.insert(row(10)).unwrap();
+        let err = table.insert(row(11)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::QuotaExceeded);
+        assert_eq!(table.get(11).unwrap(), None);
+
+        // The two page file has no room to split the leaf and possibly the root
+        table.set_limits(Limits {
+            max_file_size: Some(4 * PAGE_SIZE as u64),
+            ..Default::default()
+        });
+        let mut inserted = 11;
+        let err = loop {
+            match table.insert(row(inserted)) {
+                Ok(()) => inserted += 1,
+                Err(e) => break e,
+            }
+        };
+        assert_eq!(err.kind(), io::ErrorKind::QuotaExceeded);
+        let stored: usize = (0..inserted).map(|i| row_size(&row(i))).sum();
+        assert!(stored <= LEAF_PAYLOAD && stored + row_size(&row(inserted)) > LEAF_PAYLOAD);
+        assert_eq!(table.get(inserted).unwrap(), None);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn profile_reports_page_reads_and_cache_hits() {
+        let path = temp_path("profile");
+        let mut table = Table::open(&path).unwrap();
+        for i in 0..200 {
+            table.insert(row(i)).unwrap();
+        }
+        table.close().unwrap();
+
+        let mut table = Table::open(&path).unwrap();
+        let (found, profile) = table.profile(|table| table.get(150).unwrap());
+        assert_eq!(found, Some(row(150)));
+        assert_eq!(profile.stats.page_reads, 2);
+        assert_eq!(profile.stats.cache_hits, 0);
+
+        let (_, profile) = table.profile(|table| table.get(151).unwrap());
+        assert_eq!(profile.stats.page_reads, 0);
+        assert_eq!(profile.stats.cache_hits, 2);
+        assert_eq!(table.stats().page_reads, 2);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn cursor_reads_a_leaf_at_a_time() {
+        let path = temp_path("cursor");
+        let mut table = Table::open(&path).unwrap();
+        for i in (0..1000).rev() {
+            table.insert(row(i * 2)).unwrap();
+        }
+
+        let mut cursor = table.cursor(201).unwrap();
+        assert_eq!(cursor.get_row().unwrap(), Some(row(202)));
+        cursor.advance();
+        let batch = cursor.next_batch(300).unwrap();
+        assert_eq!(batch, (102..402).map(|i| row(i * 2)).collect::<Vec<_>>());
+        assert_eq!(cursor.next().unwrap().unwrap(), row(804));
+
+        // A full scan touches each leaf once rather than descending per row
+        let (rows, profile) = table.profile(|table| {
+            let cursor = table.cursor(i32::MIN).unwrap();
+            cursor.collect::<io::Result<Vec<Row>>>().unwrap()
+        });
+        assert_eq!(rows.len(), 1000);
+        let leaves = table.disk_usage().unwrap().leaf_pages as u64;
+        assert!(profile.stats.cache_hits + profile.stats.page_reads <= leaves + 2);
+        assert!(table.cursor(5000).unwrap().next().is_none());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn pinned_pages_survive_eviction() {
+        let leaves: Vec<Vec<i32>> = (0..10).map(|i| vec![i]).collect();
+        let mut pager = build_pager(&leaves);
+        pager.path = temp_path("pinned");
+        pager.optimize(ONE_ROW_PER_LEAF).unwrap();
+
+        let mut pager = Pager::open(&pager.path).unwrap();
+        pager.set_cache_size(2);
+        let leaf = pager.fetch_page(1).unwrap();
+        let root = pager.fetch_page_mut(0).unwrap();
+        for key in 2..10 {
+            pager.find_row_by_key(key).unwrap().unwrap();
+        }
+        assert!(pager.pages.contains_key(&0) && pager.pages.contains_key(&1));
+        assert_eq!(leaf.page().get_row(0), Some(&row(0)));
+
+        // A page changed through a guard stays dirty until the guard is gone
+        if let Node::Internal(internal) = &mut root.page_mut().node {
+            internal.size = 99;
+        }
+        pager.flush().unwrap();
+        assert!(pager.dirty.contains(&0));
+        drop((leaf, root));
+        pager.flush().unwrap();
+        assert!(pager.dirty.is_empty());
+        pager.set_cache_size(1);
+        pager.find_row_by_key(5).unwrap().unwrap();
+        assert!(!pager.pages.contains_key(&1));
+        std::fs::remove_file(&pager.path).unwrap();
+    }
+
+    #[test]
+    fn lru_and_clock_pick_different_victims() {
+        let mut lru = LruPolicy::default();
+        let mut clock = ClockPolicy::default();
+        for policy in [&mut lru as &mut dyn EvictionPolicy, &mut clock] {
+            for page_num in 1..=3 {
+                policy.inserted(page_num);
+            }
+            policy.accessed(2);
+            policy.accessed(1);
+        }
+        // LRU goes by recency, clock only by whether a page was used at all
+        assert_eq!(lru.victim(&|_| true), Some(3));
+        assert_eq!(clock.victim(&|_| true), Some(3));
+        lru.accessed(3);
+        clock.accessed(3);
+        assert_eq!(lru.victim(&|_| true), Some(2));
+        assert_eq!(clock.victim(&|_| true), Some(1));
+        assert_eq!(lru.victim(&|page_num| page_num != 2), Some(1));
+        lru.removed(2);
+        clock.removed(1);
+        assert_eq!(lru.victim(&|_| true), Some(1));
+        assert_eq!(clock.victim(&|_| true), Some(2));
+    }
+
+    #[test]
+    fn clock_policy_selected_at_open() {
+        let path = temp_path("clock");
+        let mut table = Table::open(&path).unwrap();
+        for i in 0..1000 {
+            table.insert(row(i)).unwrap();
+        }
+        table.close().unwrap();
+
+        let mut table = Table::open_options()
+            .cache_size(4)
+            .eviction_policy(ClockPolicy::default())
+            .open(&path)
+            .unwrap();
+        for i in (0..1000).step_by(7) {
+            assert_eq!(table.get(i).unwrap(), Some(row(i)));
+        }
+        assert!(table.pager.pages.len() <= 4);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn warm_cache_preloads_pages_from_last_close() {
+        let path = temp_path("warm");
+        let mut table = Table::open_options().warm_cache(true).open(&path).unwrap();
+        for i in 0..1000 {
+            table.insert(row(i)).unwrap();
+        }
+        table.close().unwrap();
+
+        let table = Table::open_options()
+            .cache_size(8)
+            .warm_cache(true)
+            .open(&path)
+            .unwrap();
+        assert_eq!(table.pager.pages.len(), 8);
+        let hot_pages_path = table.pager.hot_pages_path();
+        drop(table);
+
+        // Without the option the list is ignored and the cache starts empty
+        let mut table = Table::open(&path).unwrap();
+        assert!(table.pager.pages.is_empty());
+        let (_, profile) = table.profile(|table| table.get(0).unwrap());
+        assert_eq!(profile.stats.page_reads, 2);
+        drop(table);
+
+        let mut table = Table::open_options().warm_cache(true).open(&path).unwrap();
+        let (_, profile) = table.profile(|table| table.get(0).unwrap());
+        assert_eq!(profile.stats.page_reads, 0);
+        drop(table);
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(hot_pages_path).unwrap();
+    }
+
+    #[test]
+    fn inspect_decodes_pages_without_changing_the_file() {
+        let path = temp_path("inspect");
+        let mut table = Table::open(&path).unwrap();
+        for i in 0..3 {
+            table.insert(row(i)).unwrap();
+        }
+        table.close().unwrap();
+        let len = std::fs::metadata(&path).unwrap().len();
+
+        let root = inspect_page(&path, 0).unwrap().to_string();
+        assert!(root.starts_with("page 0: internal\n  parent: none\n"));
+        assert!(root.contains("page 1, max key 2"));
+
+        let leaf = inspect_page(&path, 1).unwrap();
+        let used = bincode::serialized_size(&leaf.node).unwrap() as usize;
+        assert_eq!(
+            leaf.cells.last().map(|(offset, len)| offset + len + 1),
+            Some(used)
+        );
+        let leaf = leaf.to_string();
+        assert!(leaf.contains("  rows: 3\n"));
+        assert!(leaf.contains(&format!(
+            "  used: {used} bytes, free: {} bytes",
+            PAGE_SIZE - used
+        )));
+
+        let err = inspect_page(&path, 5).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), len);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn disk_usage_accounts_for_every_page() {
+        let path = temp_path("disk-usage");
+        let mut table = Table::open(&path).unwrap();
+        assert_eq!(table.disk_usage().unwrap(), DiskUsage::default());
+        for i in 0..1000 {
+            table.insert(row(i)).unwrap();
+        }
+        table.close().unwrap();
+
+        let mut table = Table::open(&path).unwrap();
+        let usage = table.disk_usage().unwrap();
+        assert_eq!(usage.internal_pages, 1);
+        assert_eq!(usage.leaf_pages, table.pager.page_count - 1);
+        assert_eq!(
+            usage.row_bytes,
+            (0..1000).map(|i| row_size(&row(i))).sum::<usize>()
+        );
+        assert_eq!(usage.unused_pages, 0);
+
+        // Shrinking the tree leaves the file as it is until it is truncated
+        table.pager.page_count += 3;
+        assert_eq!(table.disk_usage().unwrap().unused_pages, 3);
+        table.pager.page_count -= 3;
+        table.optimize(1.0).unwrap();
+        let optimized = table.disk_usage().unwrap();
+        assert!(optimized.leaf_pages < usage.leaf_pages);
+        assert_eq!(
+            optimized.file_bytes,
+            ((optimized.leaf_pages + 1) * PAGE_SIZE) as u64
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn archive_round_trips_and_rejects_corruption() {
+        let source_path = temp_path("archive-source");
+        let archive_path = temp_path("archive");
+        let mut source = Table::open(&source_path).unwrap();
+        for i in (0..3000).rev() {
+            source.insert(row(i)).unwrap();
+        }
+        assert_eq!(source.export_archive(&archive_path).unwrap(), 3000);
+
+        let target_path = temp_path("archive-target");
+        let mut target = Table::open(&target_path).unwrap();
+        assert_eq!(target.import_archive(&archive_path).unwrap(), 3000);
+        let rows: Vec<Row> = target
+            .cursor(i32::MIN)
+            .unwrap()
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(rows, (0..3000).map(row).collect::<Vec<_>>());
+
+        // Flip one byte in the last row frame, nothing should be imported
+        let mut bytes = std::fs::read(&archive_path).unwrap();
+        let len = bytes.len();
+        bytes[len - 30] ^= 0xff;
+        std::fs::write(&archive_path, &bytes).unwrap();
+        let empty_path = temp_path("archive-empty");
+        let mut empty = Table::open(&empty_path).unwrap();
+        let err = empty.import_archive(&archive_path).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(empty.cursor(i32::MIN).unwrap().count(), 0);
+
+        std::fs::write(&archive_path, &bytes[..len / 2]).unwrap();
+        let err = empty.import_archive(&archive_path).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        drop((source, target, empty));
+        for path in [source_path, archive_path, target_path, empty_path] {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+
+    #[test]
+    fn temporary_table_spills_and_is_deleted() {
+        let mut table = Table::temporary().unwrap();
+        let path = table.pager.path.clone();
+        assert_ne!(Table::temporary().unwrap().pager.path, path);
+
+        for i in 0..100 {
+            table.insert(row(i)).unwrap();
+        }
+        assert_eq!(table.stats().page_writes, 0);
+        table.set_flush_threshold(Some(4));
+        for i in 100..3000 {
+            table.insert(row(i)).unwrap();
+        }
+        assert!(table.stats().page_writes > 0);
+        assert_eq!(table.get(1234).unwrap(), Some(row(1234)));
+        assert_eq!(table.cursor(0).unwrap().count(), 3000);
+
+        table.close().unwrap();
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn flush_threshold_never_writes_a_leaf_before_its_split() {
+        let path = temp_path("threshold-zero");
+        for filtered in [false, true] {
+            let open = || {
+                let mut options = Table::open_options();
+                if filtered {
+                    options = options.page_filter(XorFilter(0x5a));
+                }
+                options.open(&path).unwrap()
+            };
+            let mut table = open();
+            table.set_flush_threshold(Some(0));
+            for i in 0..5000 {
+                table.insert(row((i * 7919) % 5000)).unwrap();
+            }
+            table.close().unwrap();
+
+            let mut table = open();
+            let rows: Vec<Row> = table.scan(..).unwrap().map(Result::unwrap).collect();
+            assert_eq!(rows, (0..5000).map(row).collect::<Vec<_>>());
+            drop(table);
+            std::fs::remove_file(&path).unwrap();
+        }
+    }
+
+    #[test]
+    fn page_buffers_are_reused() {
+        let path = temp_path("buffer-pool");
+        let mut table = Table::open(&path).unwrap();
+        table.set_flush_threshold(Some(2));
+        for i in 0..3000 {
+            table.insert(row(i)).unwrap();
+        }
+        table.close().unwrap();
+
+        let mut table = Table::open(&path).unwrap();
+        assert_eq!(table.cursor(0).unwrap().count(), 3000);
+        let stats = table.stats();
+        assert!(stats.page_reads > 20);
+        assert_eq!(stats.buffer_allocations, 1);
+        drop(table);
+
+        let mut table = Table::open(&path).unwrap();
+        table.set_buffer_pool_limit(0);
+        assert_eq!(table.cursor(0).unwrap().count(), 3000);
+        let stats = table.stats();
+        assert_eq!(stats.buffer_allocations, stats.page_reads);
+        drop(table);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[derive(Debug)]
+    struct XorFilter(u8);
+
+    impl PageFilter for XorFilter {
+        fn encode(&self, page_num: usize, bytes: &[u8]) -> io::Result<Vec<u8>> {
+            Ok(bytes.iter().map(|b| b ^ self.0 ^ page_num as u8).collect())
+        }
+
+        fn decode(&self, page_num: usize, bytes: &[u8]) -> io::Result<Vec<u8>> {
+            self.encode(page_num, bytes)
+        }
+    }
+
+    #[derive(Debug)]
+    struct DeflateFilter;
+
+    impl PageFilter for DeflateFilter {
+        fn encode(&self, _page_num: usize, bytes: &[u8]) -> io::Result<Vec<u8>> {
+            Ok(miniz_oxide::deflate::compress_to_vec(bytes, 6))
+        }
+
+        fn decode(&self, _page_num: usize, bytes: &[u8]) -> io::Result<Vec<u8>> {
+            miniz_oxide::inflate::decompress_to_vec(bytes)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))
+        }
+    }
+
+    #[test]
+    fn page_filters_apply_on_write_and_reverse_on_read() {
+        let path = temp_path("page-filter");
+        let open = || {
+            Table::open_options()
+                .page_filter(DeflateFilter)
+                .page_filter(XorFilter(0x5a))
+                .open(&path)
+                .unwrap()
+        };
+        let mut table = open();
+        for i in 0..2000 {
+            table.insert(row(i)).unwrap();
+        }
+        table.close().unwrap();
+
+        let raw = std::fs::read(&path).unwrap();
+        assert!(!raw.windows(6).any(|w| w == b"row123"));
+        // Decoding in the wrong order fails instead of returning garbage rows
+        let leaf = &raw[PAGE_SIZE..2 * PAGE_SIZE];
+        assert!(DeflateFilter.decode(1, &leaf[4..]).is_err());
+
+        let mut table = open();
+        let rows: Vec<Row> = table.cursor(0).unwrap().map(Result::unwrap).collect();
+        assert_eq!(rows, (0..2000).map(row).collect::<Vec<_>>());
+        drop(table);
+
+        // A wrong key decodes to garbage, which is an error and not a poisoning
+        let mut table = Table::open_options()
+            .page_filter(DeflateFilter)
+            .page_filter(XorFilter(0x33))
+            .open(&path)
+            .unwrap();
+        assert_eq!(
+            table.cursor(0).err().unwrap().kind(),
+            io::ErrorKind::InvalidData
+        );
+        assert_eq!(table.get(5).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        drop(table);
+        let mut table = Table::open_options()
+            .page_filter(XorFilter(0x33))
+            .open(&path)
+            .unwrap();
+        assert_eq!(table.get(5).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        assert!(!table.poisoned);
+        drop(table);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn full_leaves_fit_behind_the_filter_length() {
+        let path = temp_path("full-leaves-filtered");
+        let open = || {
+            Table::open_options()
+                .page_filter(XorFilter(0x5a))
+                .append_only(true)
+                .open(&path)
+                .unwrap()
+        };
+        // Append-only splits leave every leaf but the last holding four of the
+        // largest rows along with a next_leaf link
+        let big_row = |id| Row {
+            id,
+            name: "x".repeat(MAX_ROW_SIZE - row_size(&Row::default())),
+        };
+        let mut table = open();
+        for i in 0..13 {
+            table.insert(big_row(i)).unwrap();
+        }
+        table.close().unwrap();
+
+        let mut table = open();
+        let rows: Vec<Row> = table.scan(..).unwrap().map(Result::unwrap).collect();
+        assert_eq!(rows, (0..13).map(big_row).collect::<Vec<_>>());
+        drop(table);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn cursor_reports_page_errors() {
+        let path = temp_path("cursor-errors");
+        let mut table = Table::open_options()
+            .page_filter(XorFilter(0x5a))
+            .open(&path)
+            .unwrap();
+        for i in 0..100 {
+            table.insert(row(i)).unwrap();
+        }
+        table.close().unwrap();
+
+        // Pages written without compression don't inflate
+        let mut table = Table::open_options()
+            .page_filter(DeflateFilter)
+            .page_filter(XorFilter(0x5a))
+            .open(&path)
+            .unwrap();
+        assert_eq!(
+            table.cursor(0).err().unwrap().kind(),
+            io::ErrorKind::InvalidData
+        );
+        assert_eq!(
+            table.scan(..).err().unwrap().kind(),
+            io::ErrorKind::InvalidData
+        );
+        assert!(table.detached_cursor(0).is_err());
+        // An ordinary error leaves the table usable, unlike a panic
+        for _ in 0..2 {
+            assert_eq!(table.get(5).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        }
+        drop(table);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn snapshot_is_compact_and_opens_read_only() {
+        let path = temp_path("snapshot-source");
+        let snapshot_path = temp_path("snapshot");
+        let mut table = Table::open(&path).unwrap();
+        for i in (0..5000).rev() {
+            table.insert(row(i)).unwrap();
+        }
+        let pages = table.export_snapshot(&snapshot_path).unwrap();
+        assert!(pages < table.pager.page_count);
+        let len = std::fs::metadata(&snapshot_path).unwrap().len();
+        assert_eq!(len, (pages * PAGE_SIZE) as u64);
+
+        let mut readers: Vec<Table> = (0..2)
+            .map(|_| {
+                Table::open_options()
+                    .read_only(true)
+                    .open(&snapshot_path)
+                    .unwrap()
+            })
+            .collect();
+        for reader in &mut readers {
+            assert_eq!(reader.disk_usage().unwrap().leaf_pages, pages - 1);
+            assert_eq!(reader.get(4321).unwrap(), Some(row(4321)));
+            assert_eq!(reader.cursor(0).unwrap().count(), 5000);
+            let err = reader.insert(row(5000)).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+        }
+
+        // A new export over the path doesn't reach readers that are already open
+        table.delete_range(..2500).unwrap();
+        table.export_snapshot(&snapshot_path).unwrap();
+        for reader in &mut readers {
+            assert_eq!(reader.get(1000).unwrap(), Some(row(1000)));
+            assert_eq!(reader.scan(..).unwrap().count(), 5000);
+            assert_eq!(reader.disk_usage().unwrap().file_bytes, len);
+        }
+        drop(readers);
+        let mut reader = Table::open_options()
+            .read_only(true)
+            .open(&snapshot_path)
+            .unwrap();
+        assert_eq!(reader.scan(..).unwrap().count(), 2500);
+        drop(reader);
+
+        let free_pages_path = table.pager.free_pages_path();
+        drop(table);
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(free_pages_path).unwrap();
+        std::fs::remove_file(&snapshot_path).unwrap();
+    }
+
+    #[test]
+    fn scan_is_key_ordered_and_bounded() {
+        fn keys(rows: KeyOrdered<impl Iterator<Item = io::Result<Row>>>) -> Vec<i32> {
+            rows.map(|row| row.unwrap().id).collect()
+        }
+
+        let path = temp_path("scan");
+        let mut table = Table::open(&path).unwrap();
+        for i in (0..1000).rev() {
+            table.insert(row(i * 2)).unwrap();
+        }
+        table.insert(row(i32::MAX)).unwrap();
+
+        assert_eq!(keys(table.scan(10..=16).unwrap()), vec![10, 12, 14, 16]);
+        assert_eq!(
+            keys(
+                table
+                    .scan((Bound::Excluded(10), Bound::Excluded(16)))
+                    .unwrap()
+            ),
+            vec![12, 14]
+        );
+        assert_eq!(
+            keys(table.scan(1990..).unwrap()),
+            vec![1990, 1992, 1994, 1996, 1998, i32::MAX]
+        );
+        assert_eq!(keys(table.scan(..).unwrap()).len(), 1001);
+        assert!(keys(
+            table
+                .scan((Bound::Included(16), Bound::Included(10)))
+                .unwrap()
+        )
+        .is_empty());
+        assert!(keys(
+            table
+                .scan((Bound::Excluded(i32::MAX), Bound::Unbounded))
+                .unwrap()
+        )
+        .is_empty());
+
+        let unordered: Unordered<_> = table.scan(..4).unwrap().into();
+        assert_eq!(unordered.count(), 2);
+        drop(table);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn leaves_split_on_encoded_size() {
+        let path = temp_path("row-bytes");
+        let mut table = Table::open(&path).unwrap();
+        let wide = |id: i32| Row {
+            id,
+            name: format!("{id:0>200}"),
+        };
+        for i in (0..500).rev() {
+            table.insert(wide(i)).unwrap();
+        }
+        let huge = Row {
+            id: 500,
+            name: "x".repeat(MAX_ROW_SIZE),
+        };
+        let err = table.insert(huge).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        table.close().unwrap();
+
+        for page_num in 0..std::fs::metadata(&path).unwrap().len() as usize / PAGE_SIZE {
+            assert!(inspect_page(&path, page_num).unwrap().used <= PAGE_SIZE);
+        }
+        let mut table = Table::open(&path).unwrap();
+        let rows: Vec<Row> = table.cursor(0).unwrap().map(Result::unwrap).collect();
+        assert_eq!(rows, (0..500).map(wide).collect::<Vec<_>>());
+        table.optimize(1.0).unwrap();
+        assert_eq!(table.get(499).unwrap(), Some(wide(499)));
+        drop(table);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    // Fails every write with StorageFull while the flag is set
+    #[derive(Debug)]
+    struct DiskFullFilter(Rc<std::cell::Cell<bool>>);
+
+    impl PageFilter for DiskFullFilter {
+        fn encode(&self, _page_num: usize, bytes: &[u8]) -> io::Result<Vec<u8>> {
+            if self.0.get() {
+                return Err(io::ErrorKind::StorageFull.into());
+            }
+            Ok(bytes.to_vec())
+        }
+
+        fn decode(&self, _page_num: usize, bytes: &[u8]) -> io::Result<Vec<u8>> {
+            Ok(bytes.to_vec())
+        }
+    }
+
+    #[test]
+    fn disk_full_refuses_inserts_and_keeps_pages() {
+        let path = temp_path("disk-full");
+        let full = Rc::new(std::cell::Cell::new(false));
+        let open = || {
+            Table::open_options()
+                .page_filter(DiskFullFilter(full.clone()))
+                .open(&path)
+                .unwrap()
+        };
+        let mut table = open();
+        table.set_flush_threshold(Some(2));
+        for i in 0..1000 {
+            table.insert(row(i)).unwrap();
+        }
+
+        // Inserts go on in memory until a write behind one of them fails
+        full.set(true);
+        let mut next = 1000;
+        let err = loop {
+            match table.insert(row(next)) {
+                Ok(()) => next += 1,
+                Err(e) => break e,
+            }
+        };
+        assert_eq!(err.kind(), io::ErrorKind::StorageFull);
+        assert!(table.pager.storage_full);
+        assert!(!table.pager.dirty.is_empty());
+        assert_eq!(table.get(next).unwrap(), None);
+        assert_eq!(table.scan(..).unwrap().count(), next as usize);
+        assert_eq!(
+            table.insert(row(next)).unwrap_err().kind(),
+            io::ErrorKind::StorageFull
+        );
+
+        full.set(false);
+        table.insert(row(next)).unwrap();
+        table.close().unwrap();
+        let mut table = open();
+        let rows: Vec<Row> = table.scan(..).unwrap().map(Result::unwrap).collect();
+        assert_eq!(rows, (0..=next).map(row).collect::<Vec<_>>());
+        drop(table);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn detached_cursor_survives_splits_deletes_and_optimize() {
+        let path = temp_path("detached-cursor");
+        let mut table = Table::open(&path).unwrap();
+        let mut expected = BTreeSet::new();
+        for i in 0..2000 {
+            table.insert(row(i * 10)).unwrap();
+            expected.insert(i * 10);
+        }
+
+        let mut cursor = table.detached_cursor(0).unwrap();
+        let mut seen = Vec::new();
+        let mut seed: u32 = 12345;
+        loop {
+            // Insert a few keys on both sides of the cursor, enough to split leaves
+            for _ in 0..3 {
+                seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                let key = (seed >> 8) as i32 % 20_000;
+                if table.insert(row(key)).is_ok() && seen.last().is_none_or(|&last| key > last) {
+                    expected.insert(key);
+                }
+            }
+            if seen.len() % 700 == 0 {
+                table.optimize(0.7).unwrap();
+            }
+            if seen.len() % 300 == 150 {
+                let start = seen.last().unwrap() - 400;
+                table.delete_range(start..start + 800).unwrap();
+                let ahead = seen.last().unwrap() + 1..start + 800;
+                expected.retain(|key| !ahead.contains(key));
+            }
+            match cursor.next(&mut table).unwrap() {
+                Some(row) => seen.push(row.id),
+                None => break,
+            }
+        }
+        assert_eq!(seen, expected.into_iter().collect::<Vec<_>>());
+        let free_pages_path = table.pager.free_pages_path();
+        drop(table);
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(free_pages_path).unwrap();
+    }
+
+    // Files kept in memory and a clock that only moves when told to
+    #[derive(Debug, Default)]
+    struct MemoryVfs {
+        files: RefCell<HashMap<PathBuf, Rc<RefCell<Vec<u8>>>>>,
+        epoch: std::cell::OnceCell<Instant>,
+        elapsed: std::cell::Cell<Duration>,
+    }
+
+    #[derive(Debug)]
+    struct MemoryFile {
+        data: Rc<RefCell<Vec<u8>>>,
+        pos: usize,
+    }
+
+    impl Read for MemoryFile {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let data = self.data.borrow();
+            let n = buf.len().min(data.len().saturating_sub(self.pos));
+            buf[..n].copy_from_slice(&data[self.pos..self.pos + n]);
+            self.pos += n;
+            Ok(n)
+        }
+    }
+
+    impl Write for MemoryFile {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let mut data = self.data.borrow_mut();
+            let end = self.pos + buf.len();
+            if data.len() < end {
+                data.resize(end, 0);
+            }
+            data[self.pos..end].copy_from_slice(buf);
+            self.pos = end;
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Seek for MemoryFile {
+        fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+            self.pos = match pos {
+                SeekFrom::Start(offset) => offset as usize,
+                SeekFrom::End(offset) => (self.data.borrow().len() as i64 + offset) as usize,
+                SeekFrom::Current(offset) => (self.pos as i64 + offset) as usize,
+            };
+            Ok(self.pos as u64)
+        }
+    }
+
+    impl VfsFile for MemoryFile {
+        fn len(&self) -> io::Result<u64> {
+            Ok(self.data.borrow().len() as u64)
+        }
+
+        fn set_len(&self, len: u64) -> io::Result<()> {
+            self.data.borrow_mut().resize(len as usize, 0);
+            Ok(())
+        }
+
+        fn sync_all(&self) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn sync_data(&self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Vfs for Rc<MemoryVfs> {
+        fn open(&self, path: &Path, writable: bool) -> io::Result<Box<dyn VfsFile>> {
+            let mut files = self.files.borrow_mut();
+            let data = match files.get(path) {
+                Some(data) => Rc::clone(data),
+                None if writable => Rc::clone(files.entry(path.to_path_buf()).or_default()),
+                None => return Err(io::ErrorKind::NotFound.into()),
+            };
+            Ok(Box::new(MemoryFile { data, pos: 0 }))
+        }
+
+        fn size(&self, path: &Path) -> io::Result<Option<u64>> {
+            Ok(self
+                .files
+                .borrow()
+                .get(path)
+                .map(|data| data.borrow().len() as u64))
+        }
+
+        fn remove(&self, path: &Path) -> io::Result<()> {
+            self.files.borrow_mut().remove(path);
+            Ok(())
+        }
+
+        fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+            let mut files = self.files.borrow_mut();
+            let data = files.remove(from).ok_or(io::ErrorKind::NotFound)?;
+            files.insert(to.to_path_buf(), data);
+            Ok(())
+        }
+
+        fn now(&self) -> Instant {
+            *self.epoch.get_or_init(Instant::now) + self.elapsed.get()
+        }
+    }
+
+    #[test]
+    fn vfs_replaces_files_and_clock() {
+        let vfs = Rc::new(MemoryVfs::default());
+        let path = Path::new("/nonexistent/quickbeam/memory.db");
+        let open = || {
+            Table::open_options()
+                .vfs(Rc::clone(&vfs))
+                .warm_cache(true)
+                .open(path)
+                .unwrap()
+        };
+        let mut table = open();
+        for i in (0..3000).rev() {
+            table.insert(row(i)).unwrap();
+        }
+        let (_, profile) = table.profile(|table| {
+            vfs.elapsed.set(Duration::from_millis(5));
+            table.get(42).unwrap()
+        });
+        assert_eq!(profile.elapsed, Duration::from_millis(5));
+        table.close().unwrap();
+        assert!(!path.exists());
+        let files = vfs.files.borrow().len();
+        assert_eq!(files, 2);
+
+        let mut table = open();
+        assert_eq!(
+            table.disk_usage().unwrap().file_bytes,
+            vfs.size(path).unwrap().unwrap()
+        );
+        let rows: Vec<Row> = table.scan(..).unwrap().map(Result::unwrap).collect();
+        assert_eq!(rows, (0..3000).map(row).collect::<Vec<_>>());
+
+        // Exports and temporary tables stay inside the VFS too
+        let snapshot_path = Path::new("/nonexistent/quickbeam/snapshot.db");
+        let archive_path = Path::new("/nonexistent/quickbeam/archive.qba");
+        table.export_snapshot(snapshot_path).unwrap();
+        assert_eq!(table.export_archive(archive_path).unwrap(), 3000);
+        let mut temporary = Table::open_options()
+            .vfs(Rc::clone(&vfs))
+            .temporary()
+            .unwrap();
+        assert_eq!(temporary.import_archive(archive_path).unwrap(), 3000);
+        temporary.set_flush_threshold(Some(0));
+        temporary.insert(row(3000)).unwrap();
+        let temporary_path = temporary.pager.path.clone();
+        assert!(vfs.size(&temporary_path).unwrap().is_some());
+        assert!(!temporary_path.exists());
+        drop(temporary);
+        assert!(vfs.size(&temporary_path).unwrap().is_none());
+        let reader = Table::open_options()
+            .vfs(Rc::clone(&vfs))
+            .read_only(true)
+            .open(snapshot_path);
+        assert_eq!(reader.unwrap().scan(..).unwrap().count(), 3000);
+    }
+
+    #[test]
+    fn delete_range_trims_leaves_and_reuses_pages() {
+        let path = temp_path("delete-range");
+        let mut table = Table::open(&path).unwrap();
+        for i in 0..5000 {
+            table.insert(row((i * 7919) % 5000)).unwrap();
+        }
+        table.set_limits(Limits {
+            max_rows: Some(5000),
+            ..Default::default()
+        });
+
+        assert_eq!(table.delete_range(1000..4000).unwrap(), 3000);
+        assert_eq!(table.delete_range(1000..4000).unwrap(), 0);
+        assert_eq!(table.get(999).unwrap(), Some(row(999)));
+        assert_eq!(table.get(1000).unwrap(), None);
+        let keys: Vec<i32> = table.scan(..).unwrap().map(|row| row.unwrap().id).collect();
+        assert_eq!(keys, (0..1000).chain(4000..5000).collect::<Vec<_>>());
+        let usage = table.disk_usage().unwrap();
+        assert!(usage.free_pages > 10);
+        assert_eq!(usage.unused_pages, 0);
+
+        // Splits take pages off the free list before growing the file
+        let page_count = table.pager.page_count;
+        for i in 1000..2000 {
+            table.insert(row(i)).unwrap();
+        }
+        assert_eq!(table.pager.page_count, page_count);
+        let free_pages = table.disk_usage().unwrap().free_pages;
+        assert!(free_pages > 0 && free_pages < usage.free_pages);
+        table.close().unwrap();
+
+        // The free list survives a reopen and is still drawn from
+        let mut table = Table::open(&path).unwrap();
+        assert_eq!(table.disk_usage().unwrap().free_pages, free_pages);
+        for i in 2000..2500 {
+            table.insert(row(i)).unwrap();
+        }
+        assert_eq!(table.pager.page_count, page_count);
+        table.delete_range(2000..2500).unwrap();
+        table.close().unwrap();
+
+        let mut table = Table::open(&path).unwrap();
+        let keys: Vec<i32> = table.scan(..).unwrap().map(|row| row.unwrap().id).collect();
+        assert_eq!(keys, (0..2000).chain(4000..5000).collect::<Vec<_>>());
+        assert_eq!(table.delete_range(i32::MIN..=i32::MIN).unwrap(), 0);
+        assert_eq!(table.delete_range(4999..).unwrap(), 1);
+        assert_eq!(table.delete_range(..).unwrap(), 2999);
+        assert_eq!(table.scan(..).unwrap().count(), 0);
+        table.insert(row(7)).unwrap();
+        assert_eq!(table.get(7).unwrap(), Some(row(7)));
+        let free_pages_path = table.pager.free_pages_path();
+        drop(table);
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(free_pages_path).unwrap();
+    }
+
+    #[test]
+    fn delete_range_with_open_start_reaches_negative_keys() {
+        let mut table = Table::temporary().unwrap();
+        for i in -5000..5000 {
+            table.insert(row(i)).unwrap();
+        }
+        assert!(table.disk_usage().unwrap().leaf_pages > 10);
+
+        assert_eq!(table.delete_range(..0).unwrap(), 5000);
+        let keys: Vec<i32> = table.scan(..).unwrap().map(|row| row.unwrap().id).collect();
+        assert_eq!(keys, (0..5000).collect::<Vec<_>>());
+        assert_eq!(table.delete_range(..=i32::MIN).unwrap(), 0);
+        assert_eq!(table.delete_range(..).unwrap(), 5000);
+    }
+
+    #[test]
+    fn latency_histogram_percentiles() {
+        let mut histogram = LatencyHistogram::default();
+        assert_eq!(histogram.percentile(99.0), Duration::ZERO);
+        for micros in 1..=10_000 {
+            histogram.record(Duration::from_micros(micros));
+        }
+        assert_eq!(histogram.count(), 10_000);
+        assert_eq!(histogram.max(), Duration::from_millis(10));
+        assert_eq!(histogram.percentile(100.0), histogram.max());
+        for (percentile, expected) in [(50.0, 5_000.0), (99.0, 9_900.0), (0.01, 1.0)] {
+            let micros = histogram.percentile(percentile).as_secs_f64() * 1e6;
+            assert!(
+                micros >= expected && micros <= expected * 1.0625,
+                "p{percentile} was {micros}us"
+            );
+        }
+        let mean = histogram.mean().as_secs_f64() * 1e6;
+        assert!((mean - 5_000.5).abs() < 1.0);
+        for nanos in [0, 15, 16, 17, 1_000, u64::MAX] {
+            let bucket = LatencyHistogram::bucket(nanos);
+            assert!(LatencyHistogram::bucket_max(bucket) >= nanos);
+            assert!(bucket == 0 || LatencyHistogram::bucket_max(bucket - 1) < nanos);
+        }
+    }
+
+    #[test]
+    fn metrics_count_each_operation() {
+        let path = temp_path("metrics");
+        let mut table = Table::open(&path).unwrap();
+        table.set_flush_threshold(Some(4));
+        for i in 0..1000 {
+            table.insert(row(i)).unwrap();
+        }
+        for i in 0..10 {
+            table.get(i * 100).unwrap();
+        }
+        table.delete_range(100..200).unwrap();
+        assert_eq!(table.scan(..).unwrap().count(), 900);
+
+        let metrics = table.metrics();
+        assert_eq!(metrics.latency(Operation::Insert).count(), 1000);
+        assert_eq!(metrics.latency(Operation::Get).count(), 10);
+        assert_eq!(metrics.latency(Operation::Delete).count(), 1);
+        assert_eq!(metrics.latency(Operation::ScanNext).count(), 901);
+        assert!(metrics.latency(Operation::Flush).count() > 0);
+        let writes = metrics.latency(Operation::PageWrite);
+        assert_eq!(writes.count(), table.stats().page_writes);
+        assert!(writes.percentile(50.0) <= writes.percentile(99.0));
+        assert!(writes.percentile(99.0) <= writes.max());
+        drop(table);
+
+        let mut table = Table::open(&path).unwrap();
+        table.get(5).unwrap();
+        let reads = table.metrics().latency(Operation::PageRead).count();
+        assert_eq!(reads, table.stats().page_reads);
+        drop(table);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn shard_ranges_cover_the_key_space_evenly() {
+        let path = temp_path("shards");
+        let mut table = Table::open(&path).unwrap();
+        assert_eq!(table.shard_ranges(4).unwrap(), vec![i32::MIN..=i32::MAX]);
+        for i in 0..20_000 {
+            table.insert(row((i * 7919) % 20_000 - 10_000)).unwrap();
+        }
+        assert_eq!(
+            table.shard_ranges(0).unwrap_err().kind(),
+            io::ErrorKind::InvalidInput
+        );
+        assert_eq!(table.shard_ranges(1).unwrap(), vec![i32::MIN..=i32::MAX]);
+
+        for n in [2, 4, 7] {
+            let ranges = table.shard_ranges(n).unwrap();
+            assert_eq!(ranges.len(), n);
+            assert_eq!(*ranges[0].start(), i32::MIN);
+            assert_eq!(*ranges[n - 1].end(), i32::MAX);
+            for pair in ranges.windows(2) {
+                assert_eq!(*pair[0].end() + 1, *pair[1].start());
+            }
+            let counts: Vec<usize> = ranges
+                .into_iter()
+                .map(|range| table.scan(range).unwrap().count())
+                .collect();
+            assert_eq!(counts.iter().sum::<usize>(), 20_000);
+            let even = 20_000 / n;
+            assert!(
+                counts
+                    .iter()
+                    .all(|&count| count > even / 2 && count < even * 3 / 2),
+                "{counts:?}"
+            );
+        }
+
+        // More shards than leaves gives one range per leaf
+        let leaves = table.disk_usage().unwrap().leaf_pages;
+        assert_eq!(table.shard_ranges(10_000).unwrap().len(), leaves);
+        drop(table);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn append_only_tables_fill_pages_and_refuse_rewrites() {
+        let path = temp_path("append-only");
+        let plain_path = temp_path("append-only-plain");
+        let mut table = TableOptions::default()
+            .append_only(true)
+            .open(&path)
+            .unwrap();
+        let mut plain = Table::open(&plain_path).unwrap();
+        for i in 0..20_000 {
+            table.insert(row(i * 2)).unwrap();
+            plain.insert(row(i * 2)).unwrap();
+        }
+
+        assert_eq!(
+            table.insert(row(39_997)).unwrap_err().kind(),
+            io::ErrorKind::InvalidInput
+        );
+        assert_eq!(
+            table.insert(row(0)).unwrap_err().kind(),
+            io::ErrorKind::AlreadyExists
+        );
+        assert_eq!(
+            table.delete_range(..).unwrap_err().kind(),
+            io::ErrorKind::PermissionDenied
+        );
+        table.insert(row(40_000)).unwrap();
+        assert_eq!(table.scan(..).unwrap().count(), 20_001);
+
+        // Plain splits leave ascending inserts with half full leaves
+        let leaves = table.disk_usage().unwrap().leaf_pages;
+        assert!(leaves * 3 / 2 < plain.disk_usage().unwrap().leaf_pages);
+
+        // Opened without the option the same file takes any key again
+        drop(table);
+        let mut table = Table::open(&path).unwrap();
+        table.insert(row(1)).unwrap();
+        assert_eq!(table.delete_range(..10).unwrap(), 6);
+        drop(table);
+        drop(plain);
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(&plain_path).unwrap();
+    }
+
+    #[test]
+    fn generate_repeats_for_the_same_seed() {
+        let spec = DataSpec::default()
+            .key_order(KeyOrder::Random)
+            .name_len(4..=32)
+            .seed(7);
+        let mut rows = Vec::new();
+        for _ in 0..2 {
+            let mut table = Table::temporary().unwrap();
+            table.generate(5000, &spec).unwrap();
+            rows.push(
+                table
+                    .scan(..)
+                    .unwrap()
+                    .collect::<io::Result<Vec<_>>>()
+                    .unwrap(),
+            );
+        }
+        assert_eq!(rows[0], rows[1]);
+        assert!(rows[0].iter().map(|row| row.id).eq(0..5000));
+        assert!(rows[0].iter().all(|row| (4..=32).contains(&row.name.len())));
+
+        let mut table = Table::temporary().unwrap();
+        table.generate(5000, &spec.clone().seed(8)).unwrap();
+        let other: Vec<Row> = table.scan(..).unwrap().map(Result::unwrap).collect();
+        assert_ne!(rows[0], other);
+        assert_eq!(
+            table.generate(1, &spec).unwrap_err().kind(),
+            io::ErrorKind::AlreadyExists
+        );
+        assert_eq!(
+            table
+                .generate(1, &DataSpec::default().name_len(RangeInclusive::new(8, 4)))
+                .unwrap_err()
+                .kind(),
+            io::ErrorKind::InvalidInput
+        );
+    }
+
+    #[test]
+    fn bench_runs_each_workload() {
+        let zipfian = Zipfian::new(1000, 0.99).unwrap();
+        let mut rng = SplitMix64(1);
+        let mut counts = vec![0; 1000];
+        for _ in 0..100_000 {
+            counts[zipfian.sample(&mut rng) as usize] += 1;
+        }
+        assert!(counts[0] > counts[1] && counts[1] > counts[100] && counts[100] > counts[999]);
+        assert!(Zipfian::new(1000, 1.0).is_err());
+
+        let mut table = Table::temporary().unwrap();
+        let spec = DataSpec::default()
+            .access(Access::Zipfian(0.99))
+            .scan_len(20);
+        assert_eq!(
+            table
+                .bench(Workload::PointRead, 1, &spec)
+                .unwrap_err()
+                .kind(),
+            io::ErrorKind::InvalidInput
+        );
+        table.generate(2000, &spec).unwrap();
+        let empty_names = spec.clone().name_len(RangeInclusive::new(8, 4));
+        for workload in [Workload::Load, Workload::Mixed { read_percent: 50 }] {
+            assert_eq!(
+                table.bench(workload, 1, &empty_names).unwrap_err().kind(),
+                io::ErrorKind::InvalidInput
+            );
+        }
+        for workload in [
+            Workload::Load,
+            Workload::PointRead,
+            Workload::Scan,
+            Workload::Mixed { read_percent: 50 },
+        ] {
+            let report = table.bench(workload, 200, &spec).unwrap();
+            assert_eq!(report.workload, workload);
+            assert_eq!(report.operations, 200);
+            assert_eq!(report.latency.count(), 200);
+            assert!(report.throughput() > 0.0);
+        }
+        let rows = table.scan(..).unwrap().count();
+        assert!(rows > 2200 && rows < 2400, "{rows}");
+        assert!(table
+            .scan(..)
+            .unwrap()
+            .map(|row| row.unwrap().id)
+            .eq(0..rows as i32));
+
+        let reports = bench(500, 50).unwrap();
+        assert_eq!(reports.len(), 4);
+        assert!(reports.iter().all(|report| report.latency.count() == 50));
+        let line = reports[3].to_string();
+        assert!(
+            line.starts_with("mixed 50% reads") && line.contains(" ops/s "),
+            "{line}"
+        );
+        assert!(line.contains("p50 ") && line.contains("p99 "), "{line}");
+    }
 }